@@ -2,7 +2,7 @@ use log::{debug, error};
 use rusty_libimobiledevice::services::afc::{AfcClient, AfcFileMode};
 
 use crate::{
-    device::{fetch_first_device, test_device_connection},
+    device::{fetch_device, test_device_connection},
     Errors, Res,
 };
 
@@ -14,15 +14,19 @@ mod ffi {
     extern "Rust" {
         type AfcFileManager;
         #[swift_bridge(associated_to = AfcFileManager)]
-        fn remove(path: String) -> Result<(), Errors>;
+        fn remove(path: String, udid: Option<String>) -> Result<(), Errors>;
         #[swift_bridge(swift_name = "createDirectory", associated_to = AfcFileManager)]
-        fn create_directory(path: String) -> Result<(), Errors>;
+        fn create_directory(path: String, udid: Option<String>) -> Result<(), Errors>;
         #[swift_bridge(swift_name = "writeFile", associated_to = AfcFileManager)]
-        fn write_file(to: String, bytes: &[u8]) -> Result<(), Errors>;
+        fn write_file(to: String, bytes: &[u8], udid: Option<String>) -> Result<(), Errors>;
         #[swift_bridge(swift_name = "copyFileOutsideAfc", associated_to = AfcFileManager)]
-        fn copy_file_outside_afc(from: String, to: String) -> Result<(), Errors>;
+        fn copy_file_outside_afc(
+            from: String,
+            to: String,
+            udid: Option<String>,
+        ) -> Result<(), Errors>;
         #[swift_bridge(swift_name = "contents", associated_to = AfcFileManager)]
-        fn contents() -> Vec<RustDirectoryEntry>;
+        fn contents(udid: Option<String>) -> Vec<RustDirectoryEntry>;
 
         type RustDirectoryEntry;
         fn path(self: &RustDirectoryEntry) -> String;
@@ -63,13 +67,13 @@ impl RustDirectoryEntry {
 
 pub struct AfcFileManager;
 impl AfcFileManager {
-    fn client() -> Res<AfcClient<'static>> {
-        if !test_device_connection() {
+    fn client(udid: Option<String>) -> Res<AfcClient<'static>> {
+        if !test_device_connection(udid.clone()) {
             error!("No device connection");
             return Err(Errors::NoConnection);
         }
 
-        match AfcClient::start_service(&fetch_first_device()?, "minimuxer") {
+        match AfcClient::start_service(&fetch_device(udid.as_deref())?, "minimuxer") {
             Ok(afc) => Ok(afc),
             Err(e) => {
                 error!("Couldn't start AFC service: {:?}", e);
@@ -78,8 +82,8 @@ impl AfcFileManager {
         }
     }
 
-    pub fn remove(path: String) -> Res<()> {
-        let client = Self::client()?;
+    pub fn remove(path: String, udid: Option<String>) -> Res<()> {
+        let client = Self::client(udid)?;
 
         match client.remove_path_and_contents(&path) {
             Ok(_) => {
@@ -93,8 +97,8 @@ impl AfcFileManager {
         }
     }
 
-    pub fn create_directory(path: String) -> Res<()> {
-        let client = Self::client()?;
+    pub fn create_directory(path: String, udid: Option<String>) -> Res<()> {
+        let client = Self::client(udid)?;
 
         match client.make_directory(&path) {
             Ok(_) => {
@@ -108,8 +112,8 @@ impl AfcFileManager {
         }
     }
 
-    pub fn write_file(to: String, bytes: &[u8]) -> Res<()> {
-        let client = Self::client()?;
+    pub fn write_file(to: String, bytes: &[u8], udid: Option<String>) -> Res<()> {
+        let client = Self::client(udid)?;
 
         let handle = match client.file_open(&to, AfcFileMode::WriteOnly) {
             Ok(c) => {
@@ -143,8 +147,8 @@ impl AfcFileManager {
     }
 
     /// Copies the file to another place on the filesystem, MAKE SURE TO
-    pub fn copy_file_outside_afc(from: String, to: String) -> Res<()> {
-        let client = Self::client()?;
+    pub fn copy_file_outside_afc(from: String, to: String, udid: Option<String>) -> Res<()> {
+        let client = Self::client(udid.clone())?;
         #[cfg(not(test))]
         let to = to[7..].to_string(); // remove the file:// prefix
 
@@ -158,7 +162,7 @@ impl AfcFileManager {
                 return Err(Errors::RwAfc);
             }
         };
-        let size = Self::file_size(from.clone())?;
+        let size = Self::file_size(from.clone(), udid)?;
         debug!("File size: {size}");
 
         let bytes = match client.file_read(handle, size) {
@@ -193,8 +197,8 @@ impl AfcFileManager {
         Ok(())
     }
 
-    fn file_size(path: String) -> Res<u32> {
-        let client = Self::client()?;
+    fn file_size(path: String, udid: Option<String>) -> Res<u32> {
+        let client = Self::client(udid)?;
 
         match client.get_file_info(&path) {
             Ok(i) => {
@@ -214,7 +218,7 @@ impl AfcFileManager {
         }
     }
 
-    pub fn contents() -> Vec<RustDirectoryEntry> {
+    pub fn contents(udid: Option<String>) -> Vec<RustDirectoryEntry> {
         fn file_info(client: &AfcClient, path: &str) -> (bool, Option<u32>) {
             match client.get_file_info(path) {
                 Ok(i) => {
@@ -286,7 +290,7 @@ impl AfcFileManager {
             entries
         }
 
-        let client = match Self::client() {
+        let client = match Self::client(udid) {
             Ok(c) => c,
             Err(_) => return vec![],
         };
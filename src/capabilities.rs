@@ -0,0 +1,132 @@
+// Jackson Coxson
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use log::error;
+
+use crate::{device_info::device_info, Errors, Res};
+
+/// An iOS version, such as 17.4.1 (21E236)
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IosVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    /// Only used to tell betas and rebuilds of the same version apart
+    pub build: Option<String>,
+}
+
+impl IosVersion {
+    /// Parses a ProductVersion (`17`, `17.4` or `17.4.1`) and its BuildVersion
+    pub fn parse(product_version: &str, build: Option<String>) -> Option<IosVersion> {
+        let mut parts = product_version.trim().split('.');
+        let mut part = || match parts.next() {
+            Some(p) => p.parse::<u32>().ok(),
+            None => Some(0),
+        };
+        let version = IosVersion {
+            major: part()?,
+            minor: part()?,
+            patch: part()?,
+            build,
+        };
+        match parts.next() {
+            Some(_) => None,
+            None => Some(version),
+        }
+    }
+
+    /// Whether this is `major.minor` or later, whatever the patch and build
+    pub fn at_least(&self, major: u32, minor: u32) -> bool {
+        (self.major, self.minor) >= (major, minor)
+    }
+}
+
+impl FromStr for IosVersion {
+    type Err = Errors;
+
+    fn from_str(s: &str) -> Res<IosVersion> {
+        match IosVersion::parse(s, None) {
+            Some(v) => Ok(v),
+            None => {
+                error!("Invalid iOS version {s:?}");
+                Err(Errors::InvalidProductVersion)
+            }
+        }
+    }
+}
+
+/// Formats like ProductVersion does, leaving out a zero patch
+impl Display for IosVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)?;
+        if self.patch != 0 {
+            write!(f, ".{}", self.patch)?;
+        }
+        Ok(())
+    }
+}
+
+/// How JIT is enabled for an app
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JitMethod {
+    /// debugserver started through lockdown, before iOS 17
+    Lockdown,
+    /// Process control and debugserver through a CoreDeviceProxy tunnel, from iOS 17.4
+    CoreDeviceProxy,
+}
+
+/// Which developer disk image the device needs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DdiMethod {
+    /// The image for the device's exact version, uploaded with its signature, before iOS 17
+    Legacy,
+    /// The personalized image, signed for the device by Apple, from iOS 17
+    Personalized,
+}
+
+/// What minimuxer can do with a device, and how
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    pub version: IosVersion,
+    /// `None` on iOS 17.0 to 17.3, which moved debugserver behind RemoteXPC but don't have
+    /// CoreDeviceProxy to tunnel to it through lockdown
+    pub jit: Option<JitMethod>,
+    pub developer_disk_image: DdiMethod,
+}
+
+impl Capabilities {
+    pub fn for_version(version: IosVersion) -> Capabilities {
+        let jit = if !version.at_least(17, 0) {
+            Some(JitMethod::Lockdown)
+        } else if version.at_least(17, 4) {
+            Some(JitMethod::CoreDeviceProxy)
+        } else {
+            None
+        };
+        let developer_disk_image = match version.at_least(17, 0) {
+            true => DdiMethod::Personalized,
+            false => DdiMethod::Legacy,
+        };
+
+        Capabilities {
+            version,
+            jit,
+            developer_disk_image,
+        }
+    }
+}
+
+/// Works out what the device can do from its version, or the first registered device's if no UDID
+/// is given
+pub fn device_capabilities(udid: Option<String>) -> Res<Capabilities> {
+    let info = device_info(udid)?;
+    match IosVersion::parse(&info.product_version, Some(info.build_version)) {
+        Some(v) => Ok(Capabilities::for_version(v)),
+        None => {
+            error!("Invalid product version {:?}", info.product_version);
+            Err(Errors::InvalidProductVersion)
+        }
+    }
+}
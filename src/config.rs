@@ -0,0 +1,275 @@
+// Jackson Coxson
+
+use std::fmt::{self, Display};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use log::{error, info};
+use once_cell::sync::Lazy;
+
+use crate::{muxer, Errors, Res};
+
+#[swift_bridge::bridge]
+mod ffi {
+    #[swift_bridge(already_declared, swift_name = "MinimuxerError")]
+    enum Errors {}
+
+    /// How the muxer serializes the plists it sends
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum ResponseFormat {
+        /// The same format as the client's request (XML or binary)
+        MatchRequest,
+        Xml,
+        Binary,
+    }
+
+    extern "Rust" {
+        type MinimuxerConfig;
+
+        fn current_config() -> MinimuxerConfig;
+        fn set_config(config: MinimuxerConfig);
+
+        fn device_address(self: &MinimuxerConfig) -> String;
+        fn set_device_address(self: &mut MinimuxerConfig, address: String) -> Result<(), Errors>;
+        fn listen_address(self: &MinimuxerConfig) -> String;
+        fn set_listen_address(self: &mut MinimuxerConfig, address: String) -> Result<(), Errors>;
+        fn listen_port(self: &MinimuxerConfig) -> u16;
+        fn set_listen_port(self: &mut MinimuxerConfig, port: u16);
+        fn listen_socket_path(self: &MinimuxerConfig) -> Option<String>;
+        fn set_listen_socket_path(self: &mut MinimuxerConfig, path: Option<String>);
+        fn response_format(self: &MinimuxerConfig) -> ResponseFormat;
+        fn set_response_format(self: &mut MinimuxerConfig, format: ResponseFormat);
+        fn connect_timeout_ms(self: &MinimuxerConfig) -> u64;
+        fn set_connect_timeout_ms(self: &mut MinimuxerConfig, ms: u64);
+        fn probe_timeout_ms(self: &MinimuxerConfig) -> u64;
+        fn set_probe_timeout_ms(self: &mut MinimuxerConfig, ms: u64);
+        fn fetch_timeout_ms(self: &MinimuxerConfig) -> u64;
+        fn set_fetch_timeout_ms(self: &mut MinimuxerConfig, ms: u64);
+        fn heartbeat_timeout_ms(self: &MinimuxerConfig) -> u64;
+        fn set_heartbeat_timeout_ms(self: &mut MinimuxerConfig, ms: u64);
+        fn heartbeat_retry_delay_ms(self: &MinimuxerConfig) -> u64;
+        fn set_heartbeat_retry_delay_ms(self: &mut MinimuxerConfig, ms: u64);
+        fn heartbeat_max_retry_delay_ms(self: &MinimuxerConfig) -> u64;
+        fn set_heartbeat_max_retry_delay_ms(self: &mut MinimuxerConfig, ms: u64);
+        fn pair_record_directory(self: &MinimuxerConfig) -> Option<String>;
+        fn set_pair_record_directory(self: &mut MinimuxerConfig, path: Option<String>);
+    }
+}
+
+pub use ffi::ResponseFormat;
+
+static CONFIG: Lazy<RwLock<MinimuxerConfig>> =
+    Lazy::new(|| RwLock::new(MinimuxerConfig::default()));
+
+/// Where minimuxer finds the device and serves its muxer, and how long it waits on things
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MinimuxerConfig {
+    /// The address the device passed to `start` is reachable at
+    pub device_address: IpAddr,
+    /// The address the muxer listens on
+    pub listen_address: IpAddr,
+    pub listen_port: u16,
+    /// If set, the muxer listens on a Unix domain socket at this path instead of the listen
+    /// address and port, like usbmuxd does at `/var/run/usbmuxd`. Only supported on Unix.
+    pub listen_socket_path: Option<PathBuf>,
+    pub response_format: ResponseFormat,
+    /// How long to wait when connecting to a service on the device for a client
+    pub connect_timeout: Duration,
+    /// How long `test_device_connection` waits for lockdownd to answer
+    pub probe_timeout: Duration,
+    /// How long to keep retrying when fetching a device from the muxer
+    pub fetch_timeout: Duration,
+    /// How long the heartbeat waits for the device's first message before reconnecting. After
+    /// that, the heartbeat waits as long as the device says it will take.
+    pub heartbeat_timeout: Duration,
+    /// How long the heartbeat waits before its first retry. Doubles with every failure in a row.
+    pub heartbeat_retry_delay: Duration,
+    /// The most the heartbeat's retry delay can grow to
+    pub heartbeat_max_retry_delay: Duration,
+    /// If set, pair records saved through the muxer are written to this directory and loaded from
+    /// it when the muxer starts. Otherwise they're only kept in memory.
+    pub pair_record_directory: Option<PathBuf>,
+}
+
+impl Default for MinimuxerConfig {
+    fn default() -> Self {
+        MinimuxerConfig {
+            // the VPN's address for the device
+            device_address: IpAddr::V4(Ipv4Addr::new(10, 7, 0, 1)),
+            listen_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            listen_port: 27015,
+            listen_socket_path: None,
+            response_format: ResponseFormat::MatchRequest,
+            connect_timeout: Duration::from_secs(5),
+            probe_timeout: Duration::from_millis(100),
+            fetch_timeout: Duration::from_secs(5),
+            heartbeat_timeout: Duration::from_secs(12),
+            heartbeat_retry_delay: Duration::from_millis(100),
+            heartbeat_max_retry_delay: Duration::from_secs(30),
+            pair_record_directory: None,
+        }
+    }
+}
+
+impl MinimuxerConfig {
+    /// Where the muxer listens
+    pub fn listen_on(&self) -> ListenAddress {
+        match &self.listen_socket_path {
+            Some(path) => ListenAddress::Unix(path.clone()),
+            None => ListenAddress::Tcp(SocketAddr::new(self.listen_address, self.listen_port)),
+        }
+    }
+
+    pub fn device_address(&self) -> String {
+        self.device_address.to_string()
+    }
+
+    /// Sets the device address from an IPv4 or IPv6 address
+    pub fn set_device_address(&mut self, address: String) -> Res<()> {
+        self.device_address = parse_address(&address)?;
+        Ok(())
+    }
+
+    pub fn listen_address(&self) -> String {
+        self.listen_address.to_string()
+    }
+
+    /// Sets the listen address from an IPv4 or IPv6 address
+    pub fn set_listen_address(&mut self, address: String) -> Res<()> {
+        self.listen_address = parse_address(&address)?;
+        Ok(())
+    }
+
+    pub fn listen_port(&self) -> u16 {
+        self.listen_port
+    }
+
+    pub fn set_listen_port(&mut self, port: u16) {
+        self.listen_port = port;
+    }
+
+    pub fn listen_socket_path(&self) -> Option<String> {
+        self.listen_socket_path
+            .as_ref()
+            .map(|p| p.to_string_lossy().into_owned())
+    }
+
+    pub fn set_listen_socket_path(&mut self, path: Option<String>) {
+        self.listen_socket_path = path.map(PathBuf::from);
+    }
+
+    pub fn response_format(&self) -> ResponseFormat {
+        self.response_format
+    }
+
+    pub fn set_response_format(&mut self, format: ResponseFormat) {
+        self.response_format = format;
+    }
+
+    pub fn connect_timeout_ms(&self) -> u64 {
+        self.connect_timeout.as_millis() as u64
+    }
+
+    pub fn set_connect_timeout_ms(&mut self, ms: u64) {
+        self.connect_timeout = Duration::from_millis(ms);
+    }
+
+    pub fn probe_timeout_ms(&self) -> u64 {
+        self.probe_timeout.as_millis() as u64
+    }
+
+    pub fn set_probe_timeout_ms(&mut self, ms: u64) {
+        self.probe_timeout = Duration::from_millis(ms);
+    }
+
+    pub fn fetch_timeout_ms(&self) -> u64 {
+        self.fetch_timeout.as_millis() as u64
+    }
+
+    pub fn set_fetch_timeout_ms(&mut self, ms: u64) {
+        self.fetch_timeout = Duration::from_millis(ms);
+    }
+
+    pub fn heartbeat_timeout_ms(&self) -> u64 {
+        self.heartbeat_timeout.as_millis() as u64
+    }
+
+    pub fn set_heartbeat_timeout_ms(&mut self, ms: u64) {
+        self.heartbeat_timeout = Duration::from_millis(ms);
+    }
+
+    pub fn heartbeat_retry_delay_ms(&self) -> u64 {
+        self.heartbeat_retry_delay.as_millis() as u64
+    }
+
+    pub fn set_heartbeat_retry_delay_ms(&mut self, ms: u64) {
+        self.heartbeat_retry_delay = Duration::from_millis(ms);
+    }
+
+    pub fn heartbeat_max_retry_delay_ms(&self) -> u64 {
+        self.heartbeat_max_retry_delay.as_millis() as u64
+    }
+
+    pub fn set_heartbeat_max_retry_delay_ms(&mut self, ms: u64) {
+        self.heartbeat_max_retry_delay = Duration::from_millis(ms);
+    }
+
+    pub fn pair_record_directory(&self) -> Option<String> {
+        self.pair_record_directory
+            .as_ref()
+            .map(|p| p.to_string_lossy().into_owned())
+    }
+
+    pub fn set_pair_record_directory(&mut self, path: Option<String>) {
+        self.pair_record_directory = path.map(PathBuf::from);
+    }
+}
+
+/// Where the muxer listens. Displays in the format libusbmuxd expects in `USBMUXD_SOCKET_ADDRESS`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(addr) => write!(f, "{addr}"),
+            ListenAddress::Unix(path) => write!(f, "UNIX:{}", path.display()),
+        }
+    }
+}
+
+/// Parses an IPv4 or IPv6 address
+pub fn parse_address(address: &str) -> Res<IpAddr> {
+    match address.parse() {
+        Ok(a) => Ok(a),
+        Err(e) => {
+            error!("Invalid address {address}: {e:?}");
+            Err(Errors::InvalidAddress)
+        }
+    }
+}
+
+/// A copy of the config minimuxer is using
+pub fn current_config() -> MinimuxerConfig {
+    CONFIG.read().unwrap().clone()
+}
+
+/// Replaces the config. The device address is used the next time minimuxer starts; if the muxer
+/// is running and its address or pair record directory changed, it is restarted with the new one.
+pub fn set_config(config: MinimuxerConfig) {
+    info!("Setting config: {config:?}");
+    let old = std::mem::replace(&mut *CONFIG.write().unwrap(), config.clone());
+
+    if (old.listen_on() != config.listen_on()
+        || old.pair_record_directory != config.pair_record_directory)
+        && muxer::listening()
+    {
+        info!("Muxer config changed, restarting the muxer");
+        muxer::listen();
+    }
+}
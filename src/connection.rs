@@ -0,0 +1,168 @@
+// Jackson Coxson
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+
+use log::info;
+use once_cell::sync::Lazy;
+
+#[swift_bridge::bridge]
+mod ffi {
+    /// Where minimuxer is in connecting to the device
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum ConnectionState {
+        /// `start` hasn't been called, or `stop` was
+        NotStarted,
+        /// Started, but no heartbeat has reached the device yet
+        WaitingForDevice,
+        /// Heartbeats are reaching the device
+        Connected,
+        /// The auto mounter is mounting the developer disk image
+        Mounting,
+        /// Connected and the developer disk image is mounted
+        Ready,
+        /// Heartbeats stopped reaching the device after it was connected
+        Lost,
+    }
+
+    extern "Swift" {
+        type ConnectionObserver;
+
+        fn state_changed(self: &ConnectionObserver, from: ConnectionState, to: ConnectionState);
+    }
+
+    extern "Rust" {
+        fn connection_state() -> ConnectionState;
+        fn add_connection_observer(observer: ConnectionObserver) -> u64;
+        fn remove_connection_observer(id: u64) -> bool;
+    }
+}
+
+use ffi::ConnectionObserver;
+pub use ffi::ConnectionState;
+
+// Observers are called from a background thread, so Swift's have to be thread safe
+unsafe impl Send for ConnectionObserver {}
+unsafe impl Sync for ConnectionObserver {}
+
+type Observer = Arc<dyn Fn(ConnectionState, ConnectionState) + Send + Sync>;
+
+static STATE: Mutex<ConnectionState> = Mutex::new(ConnectionState::NotStarted);
+static OBSERVERS: Lazy<Mutex<Vec<(u64, Observer)>>> = Lazy::new(|| Mutex::new(vec![]));
+
+/// Transitions are delivered in order on their own thread, so observers can call back into
+/// minimuxer (to restart it, for example) without deadlocking
+static TRANSITIONS: Lazy<Mutex<Sender<(ConnectionState, ConnectionState)>>> = Lazy::new(|| {
+    let (sender, receiver) = channel::<(ConnectionState, ConnectionState)>();
+    std::thread::Builder::new()
+        .name("connection-observers".to_string())
+        .spawn(move || {
+            for (from, to) in receiver {
+                let observers: Vec<Observer> = OBSERVERS
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(_, o)| o.clone())
+                    .collect();
+                for observer in observers {
+                    observer(from, to);
+                }
+            }
+        })
+        .unwrap();
+    Mutex::new(sender)
+});
+
+pub fn connection_state() -> ConnectionState {
+    *STATE.lock().unwrap()
+}
+
+/// Calls `observer` with every transition from now on. Returns an ID for
+/// `remove_connection_observer`.
+pub fn observe_connection(
+    observer: impl Fn(ConnectionState, ConnectionState) + Send + Sync + 'static,
+) -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    OBSERVERS.lock().unwrap().push((id, Arc::new(observer)));
+    id
+}
+
+pub fn add_connection_observer(observer: ConnectionObserver) -> u64 {
+    observe_connection(move |from, to| observer.state_changed(from, to))
+}
+
+/// Returns `false` if there was no observer with the ID
+pub fn remove_connection_observer(id: u64) -> bool {
+    let mut observers = OBSERVERS.lock().unwrap();
+    let count = observers.len();
+    observers.retain(|(i, _)| *i != id);
+    observers.len() != count
+}
+
+/// Moves to the state `next` picks for the current one, if it picks one
+fn transition(next: impl FnOnce(ConnectionState) -> Option<ConnectionState>) {
+    let mut state = STATE.lock().unwrap();
+    let to = match next(*state) {
+        Some(s) if s != *state => s,
+        _ => return,
+    };
+    let from = std::mem::replace(&mut *state, to);
+    info!("Connection state changed from {from:?} to {to:?}");
+    // sent under the state lock so observers see transitions in the order they happened
+    let _ = TRANSITIONS.lock().unwrap().send((from, to));
+}
+
+/// minimuxer started
+pub fn started() {
+    transition(|state| match state {
+        ConnectionState::NotStarted => Some(ConnectionState::WaitingForDevice),
+        _ => None,
+    });
+}
+
+/// minimuxer stopped
+pub fn stopped() {
+    transition(|_| Some(ConnectionState::NotStarted));
+}
+
+/// A heartbeat reached the device
+pub fn device_attached(dmg_mounted: bool) {
+    transition(|state| match state {
+        ConnectionState::WaitingForDevice | ConnectionState::Lost => Some(match dmg_mounted {
+            true => ConnectionState::Ready,
+            false => ConnectionState::Connected,
+        }),
+        _ => None,
+    });
+}
+
+/// Heartbeats no longer reach any device
+pub fn device_lost() {
+    transition(|state| match state {
+        ConnectionState::Connected | ConnectionState::Mounting | ConnectionState::Ready => {
+            Some(ConnectionState::Lost)
+        }
+        _ => None,
+    });
+}
+
+/// The auto mounter started trying to mount the developer disk image
+pub fn mounting() {
+    transition(|state| match state {
+        ConnectionState::Connected => Some(ConnectionState::Mounting),
+        _ => None,
+    });
+}
+
+/// The auto mounter finished a mount attempt
+pub fn mount_finished(success: bool) {
+    transition(|state| match (state, success) {
+        (ConnectionState::Connected | ConnectionState::Mounting, true) => {
+            Some(ConnectionState::Ready)
+        }
+        (ConnectionState::Mounting, false) => Some(ConnectionState::Connected),
+        _ => None,
+    });
+}
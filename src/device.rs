@@ -1,6 +1,10 @@
-use std::{sync::atomic::Ordering, time::Duration};
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
 
-use crate::{muxer::STARTED, Errors, Res};
+use crate::{config::current_config, muxer::STARTED, registry::DEVICES, Errors, Res};
+use ::idevice::provider::TcpProvider;
 use log::{error, info};
 use rusty_libimobiledevice::idevice::{self, Device};
 
@@ -11,53 +15,85 @@ mod ffi {
 
     extern "Rust" {
         fn fetch_udid() -> Option<String>;
-        fn test_device_connection() -> bool;
+        fn test_device_connection(udid: Option<String>) -> bool;
     }
 }
 
-/// Waits for the muxer to return the device
+/// Waits for the muxer to return the device with the given UDID, or the first registered device
+/// if no UDID is given
 ///
 /// This ensures that the muxer is running
 ///
-/// Returns an error once the timeout expires
-///
-/// Timeout is 5 seconds, 250 ms sleep between attempts
-pub fn fetch_first_device() -> Res<Device> {
-    const TIMEOUT: u16 = 5000;
-    const SLEEP: u16 = 250;
+/// Returns an error once the config's `fetch_timeout` expires, with 250 ms sleep between attempts
+pub fn fetch_device(udid: Option<&str>) -> Res<Device> {
+    const SLEEP: Duration = Duration::from_millis(250);
+
+    let udid = match DEVICES.get(udid) {
+        Some(d) => d.udid,
+        None => {
+            error!("No registered device for {udid:?}");
+            return Err(Errors::NoDevice);
+        }
+    };
 
-    let mut t = TIMEOUT;
+    let timeout = current_config().fetch_timeout;
+    let started = Instant::now();
     loop {
-        match idevice::get_first_device() {
+        match idevice::get_device(udid.clone()) {
             Ok(d) => return Ok(d),
             Err(e) => {
-                t -= SLEEP;
-                if t == 0 {
-                    error!("Couldn't fetch first device: {:?}", e);
+                if started.elapsed() + SLEEP >= timeout {
+                    error!("Couldn't fetch device {udid}: {:?}", e);
                     return Err(Errors::NoDevice);
                 }
             }
         }
-        std::thread::sleep(Duration::from_millis(SLEEP.into()));
+        std::thread::sleep(SLEEP);
     }
 }
 
+/// Builds an idevice provider that connects straight to the device at its registered address
+pub fn fetch_provider(udid: Option<&str>, label: &str) -> Res<TcpProvider> {
+    let device = match DEVICES.get(udid) {
+        Some(d) => d,
+        None => {
+            error!("No registered device for {udid:?}");
+            return Err(Errors::NoDevice);
+        }
+    };
+    let pairing_file = device.pairing_file.to_idevice()?;
+
+    Ok(TcpProvider {
+        addr: device.address,
+        pairing_file,
+        label: label.to_string(),
+    })
+}
+
 /// Tests if the device is on and listening without jumping through hoops
-pub fn test_device_connection() -> bool {
+pub fn test_device_connection(udid: Option<String>) -> bool {
     #[cfg(test)]
     {
-        info!("Skipping device connection test since we're in a test");
+        info!("Skipping device connection test for {udid:?} since we're in a test");
         true
     }
 
     #[cfg(not(test))]
     {
-        use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream};
+        use std::net::{SocketAddr, TcpStream};
+
+        // lockdownd listens on the same port on every device
+        const LOCKDOWN_PORT: u16 = 62078;
+
+        let address = match DEVICES.get(udid.as_deref()) {
+            Some(d) => d.address,
+            None => return false,
+        };
 
         // Connect to lockdownd's socket
         TcpStream::connect_timeout(
-            &SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 7, 0, 1), 62078)),
-            Duration::from_millis(100),
+            &SocketAddr::new(address, LOCKDOWN_PORT),
+            current_config().probe_timeout,
         )
         .is_ok()
     }
@@ -71,7 +107,7 @@ pub fn fetch_udid() -> Option<String> {
         return None;
     }
 
-    match fetch_first_device().map(|d| d.get_udid()) {
+    match fetch_device(None).map(|d| d.get_udid()) {
         Ok(s) => {
             info!("Success: {}", s);
             Some(s)
@@ -0,0 +1,163 @@
+// Jackson Coxson
+
+use log::{error, info};
+use plist::{Dictionary, Value};
+
+use crate::{
+    lockdown::{read_value, with_lockdown},
+    registry::DEVICES,
+    Errors, Res,
+};
+
+#[swift_bridge::bridge]
+mod ffi {
+    #[swift_bridge(already_declared, swift_name = "MinimuxerError")]
+    enum Errors {}
+
+    extern "Rust" {
+        type DeviceInfo;
+
+        fn device_info(udid: Option<String>) -> Result<DeviceInfo, Errors>;
+
+        fn device_name(self: &DeviceInfo) -> String;
+        fn product_type(self: &DeviceInfo) -> String;
+        fn product_version(self: &DeviceInfo) -> String;
+        fn build_version(self: &DeviceInfo) -> String;
+        fn hardware_model(self: &DeviceInfo) -> String;
+        fn unique_chip_id(self: &DeviceInfo) -> u64;
+        fn cpu_architecture(self: &DeviceInfo) -> String;
+        fn developer_mode_status(self: &DeviceInfo) -> Option<bool>;
+    }
+}
+
+/// The lockdown keys `DeviceInfo` is built from, all in the default domain
+const KEYS: [&str; 7] = [
+    "DeviceName",
+    "ProductType",
+    "ProductVersion",
+    "BuildVersion",
+    "HardwareModel",
+    "UniqueChipID",
+    "CPUArchitecture",
+];
+const DEVELOPER_MODE_DOMAIN: &str = "com.apple.security.mac.amfi";
+
+/// What the device says about itself through lockdown
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub device_name: String,
+    /// The model identifier, such as `iPhone15,2`
+    pub product_type: String,
+    /// The iOS version, such as `17.4.1`
+    pub product_version: String,
+    pub build_version: String,
+    /// The board, such as `D73AP`
+    pub hardware_model: String,
+    pub unique_chip_id: u64,
+    pub cpu_architecture: String,
+    /// Whether developer mode is on. `None` before iOS 16, which doesn't have it.
+    pub developer_mode_status: Option<bool>,
+}
+
+impl DeviceInfo {
+    /// Builds the info from lockdown's values. Fails with the key that's missing or has the
+    /// wrong type.
+    pub fn from_lockdown(
+        values: &Dictionary,
+        developer_mode_status: Option<bool>,
+    ) -> Result<DeviceInfo, &'static str> {
+        let string = |key: &'static str| -> Result<String, &'static str> {
+            match values.get(key).and_then(Value::as_string) {
+                Some(s) => Ok(s.to_string()),
+                None => Err(key),
+            }
+        };
+
+        Ok(DeviceInfo {
+            device_name: string("DeviceName")?,
+            product_type: string("ProductType")?,
+            product_version: string("ProductVersion")?,
+            build_version: string("BuildVersion")?,
+            hardware_model: string("HardwareModel")?,
+            unique_chip_id: values
+                .get("UniqueChipID")
+                .and_then(Value::as_unsigned_integer)
+                .ok_or("UniqueChipID")?,
+            cpu_architecture: string("CPUArchitecture")?,
+            developer_mode_status,
+        })
+    }
+
+    pub fn device_name(&self) -> String {
+        self.device_name.clone()
+    }
+
+    pub fn product_type(&self) -> String {
+        self.product_type.clone()
+    }
+
+    pub fn product_version(&self) -> String {
+        self.product_version.clone()
+    }
+
+    pub fn build_version(&self) -> String {
+        self.build_version.clone()
+    }
+
+    pub fn hardware_model(&self) -> String {
+        self.hardware_model.clone()
+    }
+
+    pub fn unique_chip_id(&self) -> u64 {
+        self.unique_chip_id
+    }
+
+    pub fn cpu_architecture(&self) -> String {
+        self.cpu_architecture.clone()
+    }
+
+    pub fn developer_mode_status(&self) -> Option<bool> {
+        self.developer_mode_status
+    }
+}
+
+/// Gets the device's info from lockdown, or the first registered device's if no UDID is given.
+/// The info is cached until the device disconnects.
+pub fn device_info(udid: Option<String>) -> Res<DeviceInfo> {
+    let registered = match DEVICES.get(udid.as_deref()) {
+        Some(d) => d,
+        None => {
+            error!("No registered device for {udid:?}");
+            return Err(Errors::NoDevice);
+        }
+    };
+    if let Some(info) = registered.device_info {
+        return Ok(info);
+    }
+    info!("Getting device info for {}", registered.udid);
+
+    let info = with_lockdown(Some(&registered.udid), |ld_client| {
+        let mut values = Dictionary::new();
+        for key in KEYS {
+            values.insert(key.to_string(), read_value(ld_client, key, "")?);
+        }
+
+        // Older versions don't have developer mode, so they don't have the key either
+        let developer_mode_status =
+            read_value(ld_client, "DeveloperModeStatus", DEVELOPER_MODE_DOMAIN)
+                .ok()
+                .and_then(|v| v.as_boolean());
+
+        match DeviceInfo::from_lockdown(&values, developer_mode_status) {
+            Ok(i) => Ok(i),
+            Err(key) => {
+                error!("Lockdown's {key} is missing or has the wrong type");
+                Err(Errors::GetLockdownValue)
+            }
+        }
+    })?;
+    info!("Got device info: {info:?}");
+
+    DEVICES.cache_device_info(&registered.udid, info.clone());
+    Ok(info)
+}
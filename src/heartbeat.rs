@@ -1,27 +1,402 @@
 // Jackson Coxson
 
-use log::{error, info, warn};
-use std::sync::atomic::{AtomicBool, Ordering};
+use log::{debug, error, info};
+use once_cell::sync::Lazy;
+use plist::{Dictionary, Value};
+use plist_plus::Plist;
+use rusty_libimobiledevice::services::heartbeat::HeartbeatClient;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::device::fetch_first_device;
+use crate::{
+    config::current_config, connection, device::fetch_device, registry::DEVICES,
+    PlistPlusConversion, RustyPlistConversion,
+};
 
-pub static LAST_BEAT_SUCCESSFUL: AtomicBool = AtomicBool::new(false);
+#[swift_bridge::bridge]
+mod ffi {
+    extern "Rust" {
+        type HeartbeatMetrics;
+
+        fn heartbeat_metrics(udid: Option<String>) -> Option<HeartbeatMetrics>;
+
+        fn last_success_timestamp(self: &HeartbeatMetrics) -> Option<u64>;
+        fn last_error(self: &HeartbeatMetrics) -> Option<String>;
+        fn consecutive_failures(self: &HeartbeatMetrics) -> u64;
+        fn average_delay_ms(self: &HeartbeatMetrics) -> Option<u64>;
+        fn reconnects(self: &HeartbeatMetrics) -> u64;
+    }
+}
+
+/// UDIDs of the devices that have a heartbeat thread running
+static BEATING: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Bumped by `stop_beats` to tell every running heartbeat thread to exit
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// How often failures are logged while the heartbeat keeps failing
+const FAILURE_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Extra time the device gets past the `Interval` it asked for before it's considered gone
+const INTERVAL_GRACE: Duration = Duration::from_secs(5);
+
+/// A message from the device's heartbeat service
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HeartbeatMessage {
+    /// The device checking that the host is still there, and how long until it checks again
+    Marco {
+        interval: Option<Duration>,
+        supports_sleepy_time: bool,
+    },
+    /// The device is going to sleep and won't send heartbeats until it wakes up
+    SleepyTime,
+}
+
+impl HeartbeatMessage {
+    pub fn parse(message: &Value) -> Result<HeartbeatMessage, String> {
+        let message = match message.as_dictionary() {
+            Some(m) => m,
+            None => return Err(format!("Heartbeat message isn't a dictionary: {message:?}")),
+        };
+        match message.get("Command").and_then(Value::as_string) {
+            Some("Marco") => Ok(HeartbeatMessage::Marco {
+                interval: message.get("Interval").and_then(|i| match i {
+                    Value::Integer(i) => i.as_unsigned().map(Duration::from_secs),
+                    Value::Real(r) => Duration::try_from_secs_f64(*r).ok(),
+                    _ => None,
+                }),
+                supports_sleepy_time: message
+                    .get("SupportsSleepyTime")
+                    .and_then(Value::as_boolean)
+                    .unwrap_or(false),
+            }),
+            Some("SleepyTime") => Ok(HeartbeatMessage::SleepyTime),
+            command => Err(format!("Unexpected heartbeat command {command:?}")),
+        }
+    }
+}
+
+/// The reply to a Marco. Saying we support sleepy time lets the device tell us before it sleeps
+/// instead of just going quiet.
+pub fn polo(supports_sleepy_time: bool) -> Value {
+    let mut polo = Dictionary::new();
+    polo.insert("Command".into(), "Polo".into());
+    if supports_sleepy_time {
+        polo.insert("SupportsSleepyTime".into(), true.into());
+    }
+    Value::Dictionary(polo)
+}
+
+/// Carries heartbeat messages to and from the device, so the protocol can be tested against a
+/// fake service
+pub trait HeartbeatTransport {
+    fn receive(&mut self, timeout: Duration) -> Result<Value, String>;
+    fn send(&mut self, message: Value) -> Result<(), String>;
+}
+
+impl HeartbeatTransport for HeartbeatClient {
+    fn receive(&mut self, timeout: Duration) -> Result<Value, String> {
+        let message = match HeartbeatClient::receive(self, timeout.as_millis() as u32) {
+            Ok(m) => m,
+            Err(e) => return Err(format!("Heartbeat recv failed: {e:?}")),
+        };
+        match Value::from_plist_plus(message) {
+            Ok(m) => Ok(m),
+            Err(e) => Err(format!("Heartbeat message isn't a plist: {e:?}")),
+        }
+    }
+
+    fn send(&mut self, message: Value) -> Result<(), String> {
+        let message = match Plist::from_rusty_plist(&message) {
+            Ok(m) => m,
+            Err(e) => return Err(format!("Failed to convert heartbeat reply: {e:?}")),
+        };
+        match HeartbeatClient::send(self, message) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("Heartbeat send failed: {e:?}")),
+        }
+    }
+}
+
+/// What happened in one heartbeat exchange
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Beat {
+    /// Got a Marco and answered it
+    Answered {
+        /// How late the Marco was, going by the `Interval` the device gave with the one before.
+        /// `None` for the first Marco of a session, or if the device didn't give an interval.
+        delay: Option<Duration>,
+    },
+    /// The device is going to sleep
+    SleepyTime,
+}
+
+/// The host's side of a heartbeat session
+pub struct HeartbeatSession<T: HeartbeatTransport> {
+    transport: T,
+    timeout: Duration,
+    /// When the device said its next Marco would come
+    next_marco: Option<Instant>,
+}
+
+impl<T: HeartbeatTransport> HeartbeatSession<T> {
+    /// `timeout` is how long to wait for the first message. After that, the device's `Interval`
+    /// decides.
+    pub fn new(transport: T, timeout: Duration) -> HeartbeatSession<T> {
+        HeartbeatSession {
+            transport,
+            timeout,
+            next_marco: None,
+        }
+    }
+
+    /// How long the session waits for the device's next message
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Waits for the device's next message and answers it
+    pub fn beat(&mut self) -> Result<Beat, String> {
+        let message = self.transport.receive(self.timeout)?;
+        let received = Instant::now();
+        match HeartbeatMessage::parse(&message)? {
+            HeartbeatMessage::Marco {
+                interval,
+                supports_sleepy_time,
+            } => {
+                let delay = self
+                    .next_marco
+                    .map(|due| received.saturating_duration_since(due));
+                self.next_marco = interval.map(|i| received + i);
+                if let Some(interval) = interval {
+                    self.timeout = interval + INTERVAL_GRACE;
+                }
+                self.transport.send(polo(supports_sleepy_time))?;
+                Ok(Beat::Answered { delay })
+            }
+            HeartbeatMessage::SleepyTime => Ok(Beat::SleepyTime),
+        }
+    }
+}
+
+/// How a device's heartbeat has been doing
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeartbeatMetrics {
+    /// When the last successful heartbeat happened
+    pub last_success: Option<SystemTime>,
+    /// Why the last failed heartbeat failed. Kept after the device recovers.
+    pub last_error: Option<String>,
+    pub consecutive_failures: u64,
+    /// How late the device's heartbeats arrive compared to the interval it asked for, smoothed
+    /// like TCP's round-trip time (each beat counts for 1/8). A slow or lossy tunnel shows up
+    /// here.
+    pub average_delay: Option<Duration>,
+    /// How many times the heartbeat had to start a new session with the device
+    pub reconnects: u64,
+}
+
+impl HeartbeatMetrics {
+    /// Records a successful beat, and how late it was if that's known
+    pub fn record_success(&mut self, delay: Option<Duration>) {
+        self.last_success = Some(SystemTime::now());
+        self.consecutive_failures = 0;
+        if let Some(delay) = delay {
+            self.average_delay = Some(match self.average_delay {
+                Some(average) => (average * 7 + delay) / 8,
+                None => delay,
+            });
+        }
+    }
+
+    pub fn record_failure(&mut self, error: String) {
+        self.last_error = Some(error);
+        self.consecutive_failures += 1;
+    }
+
+    /// Milliseconds since the Unix epoch
+    pub fn last_success_timestamp(&self) -> Option<u64> {
+        self.last_success
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as u64)
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.clone()
+    }
+
+    pub fn consecutive_failures(&self) -> u64 {
+        self.consecutive_failures
+    }
+
+    pub fn average_delay_ms(&self) -> Option<u64> {
+        self.average_delay.map(|d| d.as_millis() as u64)
+    }
+
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+}
+
+/// The heartbeat metrics for the device, or the first registered device if no UDID is given
+pub fn heartbeat_metrics(udid: Option<String>) -> Option<HeartbeatMetrics> {
+    DEVICES.get(udid.as_deref()).map(|d| d.heartbeat)
+}
+
+/// Waits longer after every failure in a row, up to a cap
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    /// The delay before the next retry
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current.min(self.max);
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+/// Logs the first failure in a row, then at most one every `FAILURE_LOG_INTERVAL`, so a device
+/// that's away (the VPN is off, for example) doesn't flood the log
+#[derive(Default)]
+pub struct FailureLog {
+    last_logged: Option<Instant>,
+    suppressed: u64,
+}
+
+impl FailureLog {
+    /// Returns whether the failure was logged at the error level
+    pub fn failure(&mut self, message: &str) -> bool {
+        if self
+            .last_logged
+            .is_some_and(|t| t.elapsed() < FAILURE_LOG_INTERVAL)
+        {
+            self.suppressed += 1;
+            debug!("{message}");
+            return false;
+        }
+
+        match self.suppressed {
+            0 => error!("{message}"),
+            n => error!("{message} ({n} more failures since the last one logged)"),
+        }
+        self.last_logged = Some(Instant::now());
+        self.suppressed = 0;
+        true
+    }
+
+    pub fn success(&mut self, udid: &str) {
+        if self.last_logged.take().is_some() {
+            info!("Heartbeat for {udid} recovered");
+        }
+        self.suppressed = 0;
+    }
+}
+
+/// Records the result of a heartbeat and lets `Listen` subscribers and connection observers know
+/// if the device came or went. Results from threads that were stopped are ignored.
+fn record_beat(udid: &str, generation: u64, result: Result<Option<Duration>, String>) {
+    if GENERATION.load(Ordering::Relaxed) != generation {
+        return;
+    }
+    let success = result.is_ok();
+    DEVICES.record_beat(udid, result);
+
+    if success {
+        let dmg_mounted = DEVICES.get(Some(udid)).is_some_and(|d| d.dmg_mounted);
+        connection::device_attached(dmg_mounted);
+    } else if !DEVICES.all().iter().any(|d| d.attached) {
+        connection::device_lost();
+    }
+}
+
+/// Records a failed heartbeat, then waits before the next attempt
+fn beat_failed(
+    udid: &str,
+    generation: u64,
+    message: String,
+    failures: &mut FailureLog,
+    backoff: &mut Backoff,
+) {
+    failures.failure(&message);
+    record_beat(udid, generation, Err(message));
+    std::thread::sleep(backoff.next_delay());
+}
+
+/// Whether the last heartbeat to the device succeeded
+pub fn last_beat_successful(udid: Option<&str>) -> bool {
+    DEVICES.get(udid).map(|d| d.attached).unwrap_or(false)
+}
+
+/// Starts a heartbeat thread for the device, unless it already has one. The thread stops once
+/// the device is removed from the registry.
+pub fn start_beat(udid: String) {
+    let generation = {
+        let mut beating = BEATING.lock().unwrap();
+        if !beating.insert(udid.clone()) {
+            return;
+        }
+        GENERATION.load(Ordering::Relaxed)
+    };
 
-pub fn start_beat() {
     std::thread::Builder::new()
-        .name("heartbeat".to_string())
+        .name(format!("heartbeat-{udid}"))
         .spawn(move || {
             // Wait for the listen thread to start
-            std::thread::sleep(std::time::Duration::from_millis(100));
-            info!("Starting heartbeat thread");
+            std::thread::sleep(Duration::from_millis(100));
+            info!("Starting heartbeat thread for {udid}");
+
+            let config = current_config();
+            let mut backoff = Backoff::new(
+                config.heartbeat_retry_delay,
+                config.heartbeat_max_retry_delay,
+            );
+            let mut failures = FailureLog::default();
+            let mut connected_before = false;
 
             loop {
-                let device = match fetch_first_device() {
+                let revision = {
+                    let mut beating = BEATING.lock().unwrap();
+                    if GENERATION.load(Ordering::Relaxed) != generation {
+                        info!("Heartbeat for {udid} was stopped");
+                        return;
+                    }
+                    match DEVICES.get(Some(&udid)) {
+                        Some(d) => d.revision,
+                        None => {
+                            beating.remove(&udid);
+                            info!("{udid} was removed, stopping heartbeat");
+                            return;
+                        }
+                    }
+                };
+
+                let device = match fetch_device(Some(&udid)) {
                     Ok(d) => d,
                     _ => {
-                        LAST_BEAT_SUCCESSFUL.store(false, Ordering::Relaxed);
-                        warn!("Could not get device from muxer for heartbeat");
-                        std::thread::sleep(std::time::Duration::from_millis(100));
+                        beat_failed(
+                            &udid,
+                            generation,
+                            "Could not get device from muxer for heartbeat".to_string(),
+                            &mut failures,
+                            &mut backoff,
+                        );
                         continue;
                     }
                 };
@@ -29,36 +404,60 @@ pub fn start_beat() {
                 let hb = match device.new_heartbeat_client("minimuxer") {
                     Ok(h) => h,
                     Err(e) => {
-                        LAST_BEAT_SUCCESSFUL.store(false, Ordering::Relaxed);
-                        error!("Failed to create heartbeat client: {:?}", e);
-                        std::thread::sleep(std::time::Duration::from_millis(100));
+                        beat_failed(
+                            &udid,
+                            generation,
+                            format!("Failed to create heartbeat client: {e:?}"),
+                            &mut failures,
+                            &mut backoff,
+                        );
                         continue;
                     }
                 };
+                if connected_before {
+                    DEVICES.record_reconnect(&udid);
+                }
+                connected_before = true;
+                let mut session = HeartbeatSession::new(hb, current_config().heartbeat_timeout);
 
                 loop {
-                    let plist = match hb.receive(12000) {
-                        Ok(p) => p,
-                        Err(e) => {
-                            LAST_BEAT_SUCCESSFUL.store(false, Ordering::Relaxed);
-                            error!("Heartbeat recv failed: {:?}", e);
+                    let delay = match session.beat() {
+                        Ok(Beat::Answered { delay }) => delay,
+                        Ok(Beat::SleepyTime) => {
+                            // Not a failure, so the device isn't reported as lost. If it's
+                            // still asleep when we reconnect, that fails and backs off as usual.
+                            info!("{udid} is going to sleep, pausing heartbeat");
+                            std::thread::sleep(session.timeout());
                             break;
                         }
-                    };
-
-                    match hb.send(plist) {
-                        Ok(_) => {}
                         Err(e) => {
-                            LAST_BEAT_SUCCESSFUL.store(false, Ordering::Relaxed);
-                            error!("Heartbeat send failed: {:?}", e);
+                            beat_failed(&udid, generation, e, &mut failures, &mut backoff);
                             break;
                         }
-                    }
+                    };
 
-                    LAST_BEAT_SUCCESSFUL.store(true, Ordering::Relaxed);
-                    info!("Heartbeat success!");
+                    record_beat(&udid, generation, Ok(delay));
+                    failures.success(&udid);
+                    backoff.reset();
+                    debug!("Heartbeat success!");
+                    if GENERATION.load(Ordering::Relaxed) != generation {
+                        break;
+                    }
+                    // Start a new session with the new pairing file
+                    if DEVICES.get(Some(&udid)).map(|d| d.revision) != Some(revision) {
+                        info!("Pairing file for {udid} changed, reconnecting heartbeat");
+                        break;
+                    }
                 }
             }
         })
         .unwrap();
 }
+
+/// Tells every heartbeat thread to stop. Threads waiting on the device exit once it answers or the
+/// wait times out.
+pub fn stop_beats() {
+    let mut beating = BEATING.lock().unwrap();
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+    beating.clear();
+}
@@ -7,7 +7,7 @@ use plist_plus::Plist;
 use rusty_libimobiledevice::services::afc::AfcFileMode;
 
 use crate::{
-    device::{fetch_first_device, test_device_connection},
+    device::{fetch_device, test_device_connection},
     Errors, PlistPlusConversion, Res,
 };
 
@@ -17,24 +17,28 @@ mod ffi {
     enum Errors {}
 
     extern "Rust" {
-        fn yeet_app_afc(bundle_id: String, ipa_bytes: &[u8]) -> Result<(), Errors>;
-        fn install_ipa(bundle_id: String) -> Result<(), Errors>;
-        fn remove_app(bundle_id: String) -> Result<(), Errors>;
+        fn yeet_app_afc(
+            bundle_id: String,
+            ipa_bytes: &[u8],
+            udid: Option<String>,
+        ) -> Result<(), Errors>;
+        fn install_ipa(bundle_id: String, udid: Option<String>) -> Result<(), Errors>;
+        fn remove_app(bundle_id: String, udid: Option<String>) -> Result<(), Errors>;
     }
 }
 
 const PKG_PATH: &str = "PublicStaging";
 
 /// Yeets an ipa to the afc jail
-pub fn yeet_app_afc(bundle_id: String, ipa_bytes: &[u8]) -> Res<()> {
+pub fn yeet_app_afc(bundle_id: String, ipa_bytes: &[u8], udid: Option<String>) -> Res<()> {
     info!("Yeeting IPA for bundle ID: {}", bundle_id);
 
-    if !test_device_connection() {
+    if !test_device_connection(udid.clone()) {
         error!("No device connection");
         return Err(Errors::NoConnection);
     }
 
-    let device = fetch_first_device()?;
+    let device = fetch_device(udid.as_deref())?;
 
     // Start an AFC client
     let afc = match device.new_afc_client("minimuxer") {
@@ -110,15 +114,15 @@ pub fn yeet_app_afc(bundle_id: String, ipa_bytes: &[u8]) -> Res<()> {
 
 /// Installs an ipa with a bundle ID
 /// Expects the ipa to be in the afc jail from yeet_app_afc
-pub fn install_ipa(bundle_id: String) -> Res<()> {
+pub fn install_ipa(bundle_id: String, udid: Option<String>) -> Res<()> {
     info!("Installing app for bundle ID: {}", bundle_id);
 
-    if !test_device_connection() {
+    if !test_device_connection(udid.clone()) {
         error!("No device connection");
         return Err(Errors::NoConnection);
     }
 
-    let device = fetch_first_device()?;
+    let device = fetch_device(udid.as_deref())?;
 
     // normally, we use client_options_new: https://github.com/jkcoxson/rusty_libimobiledevice/blob/master/src/services/instproxy.rs#L123
     // however, this literally just creates an empty dictionary: https://github.com/libimobiledevice/libimobiledevice/blob/master/src/installation_proxy.c#L919-L922
@@ -156,15 +160,15 @@ pub fn install_ipa(bundle_id: String) -> Res<()> {
 }
 
 /// Removes an app from the device
-pub fn remove_app(bundle_id: String) -> Res<()> {
+pub fn remove_app(bundle_id: String, udid: Option<String>) -> Res<()> {
     info!("Removing app for {}", bundle_id);
 
-    if !test_device_connection() {
+    if !test_device_connection(udid.clone()) {
         error!("No device connection");
         return Err(Errors::NoConnection);
     }
 
-    let device = fetch_first_device()?;
+    let device = fetch_device(udid.as_deref())?;
 
     let instproxy_client = match device.new_instproxy_client("minimuxer-remove-app") {
         Ok(i) => i,
@@ -1,24 +1,17 @@
 // Jackson Coxson
 
 
-use std::{
-    net::{Ipv4Addr, SocketAddrV4},
-    str::FromStr,
-};
+use idevice::{core_device_proxy::CoreDeviceProxy, debug_proxy::DebugProxyClient, IdeviceService};
+use std::collections::BTreeMap;
 
-use idevice::{
-    core_device_proxy::CoreDeviceProxy,
-    debug_proxy::DebugProxyClient,
-    provider::{IdeviceProvider, TcpProvider},
-    usbmuxd::UsbmuxdConnection,
-    IdeviceService,
-};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use plist::{Dictionary, Value};
 use plist_plus::Plist;
-use rusty_libimobiledevice::services::instproxy::InstProxyClient;
+use rusty_libimobiledevice::{idevice::Device, services::instproxy::InstProxyClient};
 
 use crate::{
-    device::{fetch_first_device, test_device_connection},
+    capabilities::{device_capabilities, JitMethod},
+    device::{fetch_device, fetch_provider, test_device_connection},
     Errors, Res, RUNTIME,
 };
 
@@ -28,114 +21,263 @@ mod ffi {
     enum Errors {}
 
     extern "Rust" {
-        fn debug_app(app_id: String) -> Result<(), Errors>;
-        fn attach_debugger(pid: u32) -> Result<(), Errors>;
+        type LaunchedApp;
+        type LaunchOptions;
+
+        fn debug_app(app_id: String, udid: Option<String>) -> Result<LaunchedApp, Errors>;
+        fn debug_app_with_options(
+            app_id: String,
+            options: LaunchOptions,
+            udid: Option<String>,
+        ) -> Result<LaunchedApp, Errors>;
+        fn attach_debugger(pid: u32, udid: Option<String>) -> Result<(), Errors>;
+
+        fn pid(self: &LaunchedApp) -> u64;
+        fn bundle_path(self: &LaunchedApp) -> String;
+        fn executable(self: &LaunchedApp) -> String;
+        fn memory_limit_disabled(self: &LaunchedApp) -> bool;
+
+        fn launch_options() -> LaunchOptions;
+        fn add_argument(self: &mut LaunchOptions, argument: String);
+        fn set_environment_variable(self: &mut LaunchOptions, name: String, value: String);
+        fn set_start_suspended(self: &mut LaunchOptions, start_suspended: bool);
+        fn set_kill_existing(self: &mut LaunchOptions, kill_existing: bool);
+        fn set_disable_memory_limit(self: &mut LaunchOptions, disable_memory_limit: bool);
     }
 }
 
-/// Debugs an app from an app ID
-pub fn debug_app(app_id: String) -> Res<()> {
-    info!("Debugging app ID: {}", app_id);
+/// How `debug_app` launches an app. The environment works on every version. Arguments only work
+/// before iOS 17, since process control takes them as a dictionary rather than a list.
+/// `start_suspended`, `kill_existing` and `disable_memory_limit` only take effect from iOS 17.4;
+/// before that, asking for them is logged as a warning and debugserver launches the app the way
+/// it always does.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LaunchOptions {
+    /// Passed to the app after its path
+    pub arguments: Vec<String>,
+    pub environment: BTreeMap<String, String>,
+    /// Start the app suspended so the debugger attaches before it runs any code
+    pub start_suspended: bool,
+    /// Kill the app first if it's running
+    pub kill_existing: bool,
+    /// Left unset, the memory limit is disabled wherever that's supported
+    pub disable_memory_limit: Option<bool>,
+}
 
-    if !test_device_connection() {
-        error!("No device connection");
-        return Err(Errors::NoConnection);
+impl Default for LaunchOptions {
+    fn default() -> Self {
+        LaunchOptions {
+            arguments: vec![],
+            environment: BTreeMap::new(),
+            start_suspended: true,
+            kill_existing: false,
+            disable_memory_limit: None,
+        }
+    }
+}
+
+impl LaunchOptions {
+    pub fn add_argument(&mut self, argument: String) {
+        self.arguments.push(argument);
+    }
+
+    pub fn set_environment_variable(&mut self, name: String, value: String) {
+        self.environment.insert(name, value);
+    }
+
+    pub fn set_start_suspended(&mut self, start_suspended: bool) {
+        self.start_suspended = start_suspended;
+    }
+
+    pub fn set_kill_existing(&mut self, kill_existing: bool) {
+        self.kill_existing = kill_existing;
+    }
+
+    pub fn set_disable_memory_limit(&mut self, disable_memory_limit: bool) {
+        self.disable_memory_limit = Some(disable_memory_limit);
+    }
+
+    /// The environment for process control
+    pub fn environment_dictionary(&self) -> Dictionary {
+        self.environment
+            .iter()
+            .map(|(name, value)| (name.clone(), Value::String(value.clone())))
+            .collect()
+    }
+
+    /// The packets that set the environment in debugserver before it launches the app
+    pub fn environment_packets(&self) -> Vec<String> {
+        self.environment
+            .iter()
+            .map(|(name, value)| {
+                let hex: String = format!("{name}={value}")
+                    .bytes()
+                    .map(|b| format!("{b:02x}"))
+                    .collect();
+                format!("QEnvironmentHexEncoded:{hex}")
+            })
+            .collect()
+    }
+}
+
+/// The options `debug_app` uses: no extra arguments or environment, started suspended and with
+/// the memory limit disabled
+pub fn launch_options() -> LaunchOptions {
+    LaunchOptions::default()
+}
+
+/// An app `debug_app` launched with JIT enabled
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LaunchedApp {
+    pub pid: u64,
+    /// Where the app's bundle is on the device
+    pub bundle_path: String,
+    /// The name of the executable in the bundle
+    pub executable: String,
+    /// Only done on iOS 17.4 and later
+    pub memory_limit_disabled: bool,
+}
+
+impl LaunchedApp {
+    pub fn pid(&self) -> u64 {
+        self.pid
+    }
+
+    pub fn bundle_path(&self) -> String {
+        self.bundle_path.clone()
     }
 
-    let device = fetch_first_device()?;
-    let ld_client = match device.new_lockdownd_client("minimuxer") {
-        Ok(l) => l,
+    pub fn executable(&self) -> String {
+        self.executable.clone()
+    }
+
+    pub fn memory_limit_disabled(&self) -> bool {
+        self.memory_limit_disabled
+    }
+}
+
+/// Where an installed app lives on the device
+struct AppPaths {
+    container: String,
+    bundle_path: String,
+    executable: String,
+}
+
+/// Looks up an installed app's container, bundle path and executable
+fn lookup_app(device: &Device, app_id: &str) -> Res<AppPaths> {
+    let instproxy_client = match device.new_instproxy_client("minimuxer") {
+        Ok(i) => i,
         Err(e) => {
-            error!("Failed to connect to lockdown: {e:?}");
-            return Err(Errors::CreateLockdown);
+            error!("Failed to create instproxy client: {:?}", e);
+            return Err(Errors::CreateInstproxy);
         }
     };
 
-    let product_version = match ld_client.get_value("ProductVersion", "") {
-        Ok(p) => p,
+    let client_opts = InstProxyClient::create_return_attributes(
+        vec![("ApplicationType".to_string(), Plist::new_string("Any"))],
+        vec![
+            "CFBundleIdentifier".to_string(),
+            "CFBundleExecutable".to_string(),
+            "CFBundlePath".to_string(),
+            "BundlePath".to_string(),
+            "Container".to_string(),
+        ],
+    );
+
+    let lookup_results = match instproxy_client.lookup(vec![app_id.to_string()], Some(client_opts))
+    {
+        Ok(apps) => {
+            debug!("Successfully looked up apps: {:?}", apps);
+            apps
+        }
         Err(e) => {
-            error!("Failed to get product version from lockdown: {e:?}");
-            return Err(Errors::GetLockdownValue);
+            error!("Error looking up apps: {:?}", e);
+            return Err(Errors::LookupApps);
+        }
+    };
+    let lookup_results = match lookup_results.dict_get_item(app_id) {
+        Ok(r) => r,
+        Err(e) => {
+            error!("App not found: {:?}", e);
+            return Err(Errors::FindApp);
         }
     };
 
-    let product_version = if let Some(product_version) = product_version
-        .get_string_val()
-        .ok()
-        .and_then(|x| x.split('.').collect::<Vec<&str>>()[0].parse::<u8>().ok())
+    let string = |key: &str| match lookup_results
+        .dict_get_item(key)
+        .and_then(|v| v.get_string_val())
     {
-        product_version
-    } else {
-        error!("Failed to get product version from plist");
-        return Err(Errors::GetLockdownValue);
+        Ok(v) => Ok(v),
+        Err(e) => {
+            error!("Error getting {key} for {app_id}: {:?}", e);
+            Err(Errors::FindApp)
+        }
     };
+    let container = string("Container")?;
+    debug!("Working directory: {}", container);
+    let executable = string("CFBundleExecutable")?;
 
-    if product_version < 17 {
-        let debug_server = match device.new_debug_server("minimuxer") {
-            Ok(d) => d,
-            Err(e) => {
-                error!("Failed to start debug server: {:?}", e);
-                return Err(Errors::CreateDebug);
-            }
-        };
+    let bundle_path = match instproxy_client.get_path_for_bundle_identifier(app_id.to_string()) {
+        Ok(p) => p,
+        Err(e) => {
+            error!("Error getting path for bundle identifier: {:?}", e);
+            return Err(Errors::BundlePath);
+        }
+    };
+    info!("Successfully found bundle path: {bundle_path}");
 
-        let instproxy_client = match device.new_instproxy_client("minimuxer") {
-            Ok(i) => i,
-            Err(e) => {
-                error!("Failed to create instproxy client: {:?}", e);
-                return Err(Errors::CreateInstproxy);
-            }
-        };
+    Ok(AppPaths {
+        container,
+        bundle_path,
+        executable,
+    })
+}
 
-        let client_opts = InstProxyClient::create_return_attributes(
-            vec![("ApplicationType".to_string(), Plist::new_string("Any"))],
-            vec![
-                "CFBundleIdentifier".to_string(),
-                "CFBundleExecutable".to_string(),
-                "CFBundlePath".to_string(),
-                "BundlePath".to_string(),
-                "Container".to_string(),
-            ],
-        );
-
-        let lookup_results = match instproxy_client.lookup(vec![app_id.clone()], Some(client_opts))
-        {
-            Ok(apps) => {
-                debug!("Successfully looked up apps: {:?}", apps);
-                apps
-            }
-            Err(e) => {
-                error!("Error looking up apps: {:?}", e);
-                return Err(Errors::LookupApps);
-            }
-        };
-        let lookup_results = lookup_results.dict_get_item(&app_id).unwrap();
+/// Gets the PID from debugserver's reply to `qProcessInfo`, such as `pid:1f4;parent-pid:1;...`
+pub fn parse_process_info_pid(response: &str) -> Option<u64> {
+    response
+        .split(';')
+        .find_map(|field| field.strip_prefix("pid:"))
+        .and_then(|pid| u64::from_str_radix(pid, 16).ok())
+}
 
-        let working_directory = match lookup_results.dict_get_item("Container") {
-            Ok(p) => p,
-            Err(e) => {
-                error!("App not found: {:?}", e);
-                return Err(Errors::FindApp);
-            }
-        };
+/// Debugs an app from an app ID, returning what was launched
+pub fn debug_app(app_id: String, udid: Option<String>) -> Res<LaunchedApp> {
+    debug_app_with_options(app_id, LaunchOptions::default(), udid)
+}
 
-        let working_directory = match working_directory.get_string_val() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Error when getting string val: {:?}", e);
-                return Err(Errors::FindApp);
-            }
-        };
-        debug!("Working directory: {}", working_directory);
+/// Debugs an app from an app ID, launching it with the given arguments, environment and options
+pub fn debug_app_with_options(
+    app_id: String,
+    options: LaunchOptions,
+    udid: Option<String>,
+) -> Res<LaunchedApp> {
+    info!("Debugging app ID: {} with {:?}", app_id, options);
+
+    if !test_device_connection(udid.clone()) {
+        error!("No device connection");
+        return Err(Errors::NoConnection);
+    }
+
+    let device = fetch_device(udid.as_deref())?;
+    let capabilities = device_capabilities(udid.clone())?;
+    let jit = match capabilities.jit {
+        Some(j) => j,
+        None => {
+            error!("JIT isn't supported on iOS {}", capabilities.version);
+            return Err(Errors::UnsupportedIosVersion);
+        }
+    };
+    let app = lookup_app(&device, &app_id)?;
 
-        let bundle_path = match instproxy_client.get_path_for_bundle_identifier(app_id) {
-            Ok(p) => p,
+    if jit == JitMethod::Lockdown {
+        let debug_server = match device.new_debug_server("minimuxer") {
+            Ok(d) => d,
             Err(e) => {
-                error!("Error getting path for bundle identifier: {:?}", e);
-                return Err(Errors::BundlePath);
+                error!("Failed to start debug server: {:?}", e);
+                return Err(Errors::CreateDebug);
             }
         };
-        info!("Successfully found bundle path: {bundle_path}");
 
         match debug_server.send_command("QSetMaxPacketSize: 1024".into()) {
             Ok(res) => info!("Successfully set max packet size: {:?}", res),
@@ -145,7 +287,7 @@ pub fn debug_app(app_id: String) -> Res<()> {
             }
         }
 
-        match debug_server.send_command(format!("QSetWorkingDir: {working_directory}").into()) {
+        match debug_server.send_command(format!("QSetWorkingDir: {}", app.container).into()) {
             Ok(res) => info!("Successfully set working directory: {:?}", res),
             Err(e) => {
                 error!("Error setting working directory: {:?}", e);
@@ -153,7 +295,29 @@ pub fn debug_app(app_id: String) -> Res<()> {
             }
         }
 
-        match debug_server.set_argv(vec![bundle_path.clone(), bundle_path]) {
+        if options.kill_existing {
+            warn!("Killing the existing process needs iOS 17.4, launching anyway");
+        }
+        if !options.start_suspended {
+            warn!("debugserver always starts the app suspended before iOS 17, launching anyway");
+        }
+        if options.disable_memory_limit == Some(true) {
+            warn!("Disabling the memory limit needs iOS 17.4, leaving it in place");
+        }
+
+        for packet in options.environment_packets() {
+            match debug_server.send_command(packet.into()) {
+                Ok(res) => debug!("Set environment variable: {:?}", res),
+                Err(e) => {
+                    error!("Error setting environment variable: {:?}", e);
+                    return Err(Errors::Environment);
+                }
+            }
+        }
+
+        let mut argv = vec![app.bundle_path.clone(), app.bundle_path.clone()];
+        argv.extend(options.arguments);
+        match debug_server.set_argv(argv) {
             Ok(res) => info!("Successfully set argv: {:?}", res),
             Err(e) => {
                 error!("Error setting argv: {:?}", e);
@@ -161,18 +325,43 @@ pub fn debug_app(app_id: String) -> Res<()> {
             }
         }
 
+        // debugserver answers OK, or E followed by why the launch failed
         match debug_server.send_command("qLaunchSuccess".into()) {
-            Ok(res) => info!("Got launch response: {:?}", res),
+            Ok(res) if res == "OK" => info!("Got launch response: {:?}", res),
+            Ok(res) => {
+                error!("App failed to launch: {:?}", res);
+                return Err(Errors::LaunchSuccess);
+            }
             Err(e) => {
                 error!("Error checking if app launched: {:?}", e);
                 return Err(Errors::LaunchSuccess);
             }
         }
 
+        let pid = match debug_server.send_command("qProcessInfo".into()) {
+            Ok(res) => match parse_process_info_pid(&res) {
+                Some(p) => p,
+                None => {
+                    error!("No PID in process info: {:?}", res);
+                    return Err(Errors::LaunchSuccess);
+                }
+            },
+            Err(e) => {
+                error!("Error getting process info: {:?}", e);
+                return Err(Errors::LaunchSuccess);
+            }
+        };
+        info!("Launched app with PID {pid}");
+
         match debug_server.send_command("D".into()) {
             Ok(res) => {
                 info!("Success: {:?}", res);
-                Ok(())
+                Ok(LaunchedApp {
+                    pid,
+                    bundle_path: app.bundle_path,
+                    executable: app.executable,
+                    memory_limit_disabled: false,
+                })
             }
             Err(e) => {
                 error!("Error detaching: {:?}", e);
@@ -180,42 +369,8 @@ pub fn debug_app(app_id: String) -> Res<()> {
             }
         }
     } else {
+        let provider = fetch_provider(udid.as_deref(), "minimuxer")?;
         RUNTIME.block_on(async move {
-            let mut uc = UsbmuxdConnection::new(
-                Box::new(
-                    match tokio::net::TcpStream::connect("127.0.0.1:27015").await {
-                        Ok(u) => u,
-                        Err(_) => {
-                            return Err(Errors::NoConnection);
-                        }
-                    },
-                ),
-                0,
-            );
-            let dev = match uc
-                .get_devices()
-                .await
-                .ok()
-                .and_then(|x| x.into_iter().next())
-            {
-                Some(d) => d.to_provider(
-                    idevice::usbmuxd::UsbmuxdAddr::TcpSocket(std::net::SocketAddr::V4(
-                        SocketAddrV4::from_str("127.0.0.1:27015").unwrap(),
-                    )),
-                    0,
-                    "asdf",
-                ),
-                None => {
-                    return Err(Errors::NoConnection);
-                }
-            };
-
-            let provider = TcpProvider {
-                addr: std::net::IpAddr::V4(Ipv4Addr::from_str("10.7.0.1").unwrap()),
-                pairing_file: dev.get_pairing_file().await.unwrap(),
-                label: "minimuxer".to_string(),
-            };
-
             let proxy = match CoreDeviceProxy::connect(&provider).await {
                 Ok(p) => p,
                 Err(e) => {
@@ -290,17 +445,36 @@ pub fn debug_app(app_id: String) -> Res<()> {
                 }
             };
 
-            let pid = match pc_client.launch_app(app_id, None, None, true, false).await {
+            // The device wants a list of arguments, but idevice only passes a dictionary
+            if !options.arguments.is_empty() {
+                warn!("Process control can't pass arguments yet, launching without them");
+            }
+            let environment = Some(options.environment_dictionary());
+            let pid = match pc_client
+                .launch_app(
+                    app_id,
+                    environment,
+                    None,
+                    options.start_suspended,
+                    options.kill_existing,
+                )
+                .await
+            {
                 Ok(p) => p,
                 Err(e) => {
                     log::warn!("Failed to launch app: {e:?}");
                     return Err(Errors::LaunchSuccess);
                 }
             };
-            debug!("Launched app with PID {pid}");
-            if let Err(e) = pc_client.disable_memory_limit(pid).await {
-                log::warn!("Failed to disable memory limit: {e:?}")
-            }
+            info!("Launched app with PID {pid}");
+            let memory_limit_disabled = options.disable_memory_limit.unwrap_or(true)
+                && match pc_client.disable_memory_limit(pid).await {
+                    Ok(()) => true,
+                    Err(e) => {
+                        log::warn!("Failed to disable memory limit: {e:?}");
+                        false
+                    }
+                };
 
             let mut adapter = rs_client.into_inner();
             if let Err(e) = adapter.close().await {
@@ -333,7 +507,12 @@ pub fn debug_app(app_id: String) -> Res<()> {
                     }
                 }
             }
-            Ok(())
+            Ok(LaunchedApp {
+                pid,
+                bundle_path: app.bundle_path,
+                executable: app.executable,
+                memory_limit_disabled,
+            })
         })
     }
 }
@@ -341,15 +520,15 @@ pub fn debug_app(app_id: String) -> Res<()> {
 /// Debugs an app from a process ID
 /// # Arguments
 /// - `pid`: Process ID. `attach_debugger` will automatically turn this into the format required by DebugServer.
-pub fn attach_debugger(pid: u32) -> Res<()> {
+pub fn attach_debugger(pid: u32, udid: Option<String>) -> Res<()> {
     info!("Debugging process ID: {}", pid);
 
-    if !test_device_connection() {
+    if !test_device_connection(udid.clone()) {
         error!("No device connection");
         return Err(Errors::NoConnection);
     }
 
-    let device = fetch_first_device()?;
+    let device = fetch_device(udid.as_deref())?;
 
     let debug_server = match device.new_debug_server("minimuxer") {
         Ok(d) => d,
@@ -1,6 +1,6 @@
 // Jackson Coxson
 
-use std::{io::Cursor, sync::atomic::Ordering};
+use std::io::Cursor;
 
 use log::info;
 use once_cell::sync::Lazy;
@@ -10,20 +10,26 @@ use plist_plus::Plist;
 use serde::Serialize;
 use tokio::runtime::{self, Runtime};
 
-use crate::device::{fetch_first_device, test_device_connection};
-use crate::heartbeat::LAST_BEAT_SUCCESSFUL;
-use crate::mounter::DMG_MOUNTED;
-use crate::muxer::STARTED;
+use crate::status::status;
 
 mod afc_file_manager;
+mod capabilities;
+mod config;
+mod connection;
 mod device;
+mod device_info;
 mod heartbeat;
 mod install;
 mod jit;
+mod lockdown;
 mod mounter;
 mod muxer;
+mod pair_records;
+mod pairing_file;
 mod provision;
 mod raw_packet;
+mod registry;
+mod status;
 #[cfg(test)]
 mod tests;
 
@@ -44,7 +50,8 @@ mod ffi {
     enum Errors {
         NoDevice,
         NoConnection,
-        PairingFile,
+        PairingFile(String),
+        InvalidAddress,
 
         CreateDebug,
         CreateInstproxy,
@@ -61,16 +68,23 @@ mod ffi {
         XpcHandshake,
         NoService,
         InvalidProductVersion,
+        UnsupportedIosVersion,
         LookupApps,
         FindApp,
         BundlePath,
         MaxPacket,
         WorkingDirectory,
         Argv,
+        Environment,
         LaunchSuccess,
         Detach,
         Attach,
 
+        /* lockdown */
+        SetLockdownValue,
+        RemoveLockdownValue,
+        InvalidLockdownValue,
+
         /* install */
         CreateAfc,
         RwAfc,
@@ -93,7 +107,7 @@ mod ffi {
     extern "Rust" {
         fn describe_error(error: Errors) -> String;
 
-        fn ready() -> bool;
+        fn ready(udid: Option<String>) -> bool;
         fn set_debug(debug: bool);
     }
 }
@@ -108,33 +122,16 @@ pub fn describe_error(error: Errors) -> String {
 /// unfortunately we can't use this type when exporting methods to swift-bridge/ffi for unknown reasons
 pub(crate) type Res<T> = Result<T, Errors>;
 
-/// Returns `false` if minimuxer is not ready, `true` if it is. Ready means:
+/// Returns `false` if minimuxer is not ready for the device (the first registered device if no
+/// UDID is given), `true` if it is. Ready means:
 /// - device connection succeeded
-/// - at least 1 device exists
+/// - the device exists
 /// - last heartbeat was a success
-/// - the developer disk image is mounted
 /// - `start` has been called and it was successful
-fn ready() -> bool {
-    let device_connection = test_device_connection();
-    let device_exists = fetch_first_device().is_ok();
-    let heartbeat_success = LAST_BEAT_SUCCESSFUL.load(Ordering::Relaxed);
-    let dmg_mounted = DMG_MOUNTED.load(Ordering::Relaxed);
-    let started = STARTED.load(Ordering::Relaxed);
-
-    if !device_connection || !device_exists || !heartbeat_success || !started {
-        info!(
-            "minimuxer is not ready. device connection succeeded: {}; at least 1 device exists: {}; last heartbeat was a success: {}; developer disk image is mounted (not counted): {}; started: {}",
-            device_connection,
-            device_exists,
-            heartbeat_success,
-            dmg_mounted,
-            started
-        );
-        return false;
-    }
-
-    info!("minimuxer is ready!");
-    true
+///
+/// Use `status` to find out which of these failed.
+fn ready(udid: Option<String>) -> bool {
+    status(udid).ready()
 }
 
 extern "C" {
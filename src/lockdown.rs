@@ -0,0 +1,264 @@
+// Jackson Coxson
+
+use log::{error, info};
+use plist::Value;
+use plist_plus::Plist;
+use rusty_libimobiledevice::services::lockdownd::LockdowndClient;
+
+use crate::{
+    device::{fetch_device, test_device_connection},
+    registry::DEVICES,
+    Errors, PlistPlusConversion, Res, RustyPlistConversion,
+};
+
+#[swift_bridge::bridge]
+mod ffi {
+    #[swift_bridge(already_declared, swift_name = "MinimuxerError")]
+    enum Errors {}
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum LockdownValueKind {
+        Array,
+        Dictionary,
+        Boolean,
+        Data,
+        Date,
+        Real,
+        Integer,
+        String,
+        Uid,
+    }
+
+    extern "Rust" {
+        type LockdownValue;
+
+        fn get_lockdown_value(
+            key: Option<String>,
+            domain: Option<String>,
+            udid: Option<String>,
+        ) -> Result<LockdownValue, Errors>;
+        fn set_lockdown_value(
+            key: String,
+            domain: Option<String>,
+            value: LockdownValue,
+            udid: Option<String>,
+        ) -> Result<(), Errors>;
+        fn remove_lockdown_value(
+            key: String,
+            domain: Option<String>,
+            udid: Option<String>,
+        ) -> Result<(), Errors>;
+
+        fn lockdown_string(value: String) -> LockdownValue;
+        fn lockdown_bool(value: bool) -> LockdownValue;
+        fn lockdown_integer(value: i64) -> LockdownValue;
+        fn lockdown_real(value: f64) -> LockdownValue;
+        fn lockdown_data(value: Vec<u8>) -> LockdownValue;
+        fn lockdown_value_from_json(json: String) -> Result<LockdownValue, Errors>;
+
+        fn kind(self: &LockdownValue) -> LockdownValueKind;
+        fn as_string(self: &LockdownValue) -> Option<String>;
+        fn as_bool(self: &LockdownValue) -> Option<bool>;
+        fn as_integer(self: &LockdownValue) -> Option<i64>;
+        fn as_unsigned(self: &LockdownValue) -> Option<u64>;
+        fn as_real(self: &LockdownValue) -> Option<f64>;
+        fn as_data(self: &LockdownValue) -> Option<Vec<u8>>;
+        fn to_json(self: &LockdownValue) -> String;
+    }
+}
+
+pub use ffi::LockdownValueKind;
+
+/// A plist value read from or written to lockdown
+#[derive(Clone, Debug, PartialEq)]
+pub struct LockdownValue(pub Value);
+
+impl LockdownValue {
+    pub fn kind(&self) -> LockdownValueKind {
+        match &self.0 {
+            Value::Array(_) => LockdownValueKind::Array,
+            Value::Dictionary(_) => LockdownValueKind::Dictionary,
+            Value::Boolean(_) => LockdownValueKind::Boolean,
+            Value::Data(_) => LockdownValueKind::Data,
+            Value::Date(_) => LockdownValueKind::Date,
+            Value::Real(_) => LockdownValueKind::Real,
+            Value::Integer(_) => LockdownValueKind::Integer,
+            Value::String(_) => LockdownValueKind::String,
+            Value::Uid(_) => LockdownValueKind::Uid,
+            // plist::Value is non-exhaustive
+            _ => LockdownValueKind::Data,
+        }
+    }
+
+    pub fn as_string(&self) -> Option<String> {
+        self.0.as_string().map(str::to_string)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        self.0.as_boolean()
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        self.0.as_signed_integer()
+    }
+
+    pub fn as_unsigned(&self) -> Option<u64> {
+        self.0.as_unsigned_integer()
+    }
+
+    pub fn as_real(&self) -> Option<f64> {
+        self.0.as_real()
+    }
+
+    pub fn as_data(&self) -> Option<Vec<u8>> {
+        self.0.as_data().map(<[u8]>::to_vec)
+    }
+
+    /// The value as JSON. Data becomes an array of bytes and dates become RFC 3339 strings.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.0).unwrap_or_else(|e| {
+            error!("Failed to convert lockdown value to JSON: {e:?}");
+            "null".to_string()
+        })
+    }
+}
+
+pub fn lockdown_string(value: String) -> LockdownValue {
+    LockdownValue(Value::String(value))
+}
+
+pub fn lockdown_bool(value: bool) -> LockdownValue {
+    LockdownValue(Value::Boolean(value))
+}
+
+pub fn lockdown_integer(value: i64) -> LockdownValue {
+    LockdownValue(Value::Integer(value.into()))
+}
+
+pub fn lockdown_real(value: f64) -> LockdownValue {
+    LockdownValue(Value::Real(value))
+}
+
+pub fn lockdown_data(value: Vec<u8>) -> LockdownValue {
+    LockdownValue(Value::Data(value))
+}
+
+/// Parses a value from JSON. Objects become dictionaries and arrays become arrays.
+pub fn lockdown_value_from_json(json: String) -> Res<LockdownValue> {
+    match serde_json::from_str(&json) {
+        Ok(v) => Ok(LockdownValue(v)),
+        Err(e) => {
+            error!("Invalid lockdown value JSON {json}: {e:?}");
+            Err(Errors::InvalidLockdownValue)
+        }
+    }
+}
+
+/// Connects to lockdown on the device, or the first registered device if no UDID is given, and
+/// runs `f` with the client in a paired session, so values that need one can be read and written
+pub fn with_lockdown<T>(udid: Option<&str>, f: impl FnOnce(&LockdowndClient) -> Res<T>) -> Res<T> {
+    let host_id = match DEVICES.get(udid) {
+        Some(d) => d.pairing_file.host_id,
+        None => {
+            error!("No registered device for {udid:?}");
+            return Err(Errors::NoDevice);
+        }
+    };
+    if !test_device_connection(udid.map(str::to_string)) {
+        error!("No device connection");
+        return Err(Errors::NoConnection);
+    }
+
+    let device = fetch_device(udid)?;
+    let ld_client = match device.new_lockdownd_client("minimuxer") {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Failed to connect to lockdown: {e:?}");
+            return Err(Errors::CreateLockdown);
+        }
+    };
+    if let Err(e) = ld_client.start_session(host_id) {
+        error!("Failed to start lockdown session: {e:?}");
+        return Err(Errors::CreateLockdown);
+    }
+    f(&ld_client)
+}
+
+/// Gets a value from the lockdown client. Without a key, gets every value in the domain.
+pub fn read_value(ld_client: &LockdowndClient, key: &str, domain: &str) -> Res<Value> {
+    let value = match ld_client.get_value(key, domain) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to get {key} in domain {domain:?} from lockdown: {e:?}");
+            return Err(Errors::GetLockdownValue);
+        }
+    };
+    match Value::from_plist_plus(value) {
+        Ok(v) => Ok(v),
+        Err(e) => {
+            error!("Failed to convert {key} from lockdown: {e:?}");
+            Err(Errors::GetLockdownValue)
+        }
+    }
+}
+
+/// Gets a value from lockdown, from the default domain if no domain is given. Without a key,
+/// gets every value in the domain as a dictionary.
+pub fn get_lockdown_value(
+    key: Option<String>,
+    domain: Option<String>,
+    udid: Option<String>,
+) -> Res<LockdownValue> {
+    let key = key.unwrap_or_default();
+    let domain = domain.unwrap_or_default();
+    info!("Getting lockdown value {key:?} in domain {domain:?}");
+
+    with_lockdown(udid.as_deref(), |ld_client| {
+        read_value(ld_client, &key, &domain).map(LockdownValue)
+    })
+}
+
+/// Sets a value in lockdown, in the default domain if no domain is given
+pub fn set_lockdown_value(
+    key: String,
+    domain: Option<String>,
+    value: LockdownValue,
+    udid: Option<String>,
+) -> Res<()> {
+    let domain = domain.unwrap_or_default();
+    info!("Setting lockdown value {key:?} in domain {domain:?} to {value:?}");
+
+    let plist = match Plist::from_rusty_plist(&value.0) {
+        Ok(p) => p,
+        Err(e) => {
+            error!("Failed to convert lockdown value: {e:?}");
+            return Err(Errors::InvalidLockdownValue);
+        }
+    };
+
+    with_lockdown(udid.as_deref(), |ld_client| {
+        match ld_client.set_value(key.as_str(), domain.as_str(), plist) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                error!("Failed to set {key} in lockdown: {e:?}");
+                Err(Errors::SetLockdownValue)
+            }
+        }
+    })
+}
+
+/// Removes a value from lockdown, from the default domain if no domain is given
+pub fn remove_lockdown_value(key: String, domain: Option<String>, udid: Option<String>) -> Res<()> {
+    let domain = domain.unwrap_or_default();
+    info!("Removing lockdown value {key:?} in domain {domain:?}");
+
+    with_lockdown(udid.as_deref(), |ld_client| {
+        match ld_client.remove_value(key.as_str(), domain.as_str()) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                error!("Failed to remove {key} from lockdown: {e:?}");
+                Err(Errors::RemoveLockdownValue)
+            }
+        }
+    })
+}
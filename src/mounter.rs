@@ -1,14 +1,20 @@
 // Jackson Coxson
 
 
-use idevice::{lockdown::LockdownClient, mobile_image_mounter::ImageMounter, provider::{IdeviceProvider, TcpProvider}, usbmuxd::UsbmuxdConnection, IdeviceService};
+use idevice::{lockdown::LockdownClient, mobile_image_mounter::ImageMounter, IdeviceService};
 use log::{debug, error, info};
 use std::{
-    io::Write, net::{Ipv4Addr, SocketAddrV4}, path::{Path, PathBuf}, str::FromStr, sync::atomic::{AtomicBool, Ordering}
+    io::Write, path::{Path, PathBuf}, sync::atomic::{AtomicU64, Ordering}
 };
 use tokio::io::AsyncWriteExt;
 
-use crate::{fetch_first_device, Errors, RUNTIME};
+use crate::{
+    capabilities::{device_capabilities, DdiMethod},
+    connection,
+    device::{fetch_device, fetch_provider},
+    registry::DEVICES,
+    Errors, RUNTIME,
+};
 
 #[swift_bridge::bridge]
 mod ffi {
@@ -16,7 +22,7 @@ mod ffi {
     enum Errors {}
 
     extern "Rust" {
-        fn start_auto_mounter(docs_path: String);
+        fn start_auto_mounter(docs_path: String, udid: Option<String>);
     }
 }
 
@@ -27,14 +33,66 @@ const DDI_URL: &str = "https://raw.githubusercontent.com/doronz88/DeveloperDiskI
 const TRUSTCACHE_URL: &str = "https://raw.githubusercontent.com/doronz88/DeveloperDiskImage/refs/heads/main/PersonalizedImages/Xcode_iOS_DDI_Personalized/Image.dmg.trustcache";
 const MANIFEST_URL: &str = "https://raw.githubusercontent.com/doronz88/DeveloperDiskImage/refs/heads/main/PersonalizedImages/Xcode_iOS_DDI_Personalized/BuildManifest.plist";
 
-pub static DMG_MOUNTED: AtomicBool = AtomicBool::new(false);
+/// Bumped by `stop_auto_mounter` to tell the running mounter thread to exit
+static MOUNTER_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Tells the auto mounter to stop and forgets whether the image was mounted
+pub fn stop_auto_mounter() {
+    MOUNTER_GENERATION.fetch_add(1, Ordering::Relaxed);
+    DEVICES.set_mounted(None, false);
+}
+
+/// A mount attempt, published as `ConnectionState::Mounting` while it runs. It's reported as
+/// failed when dropped unless it mounted the image, so every early exit is covered.
+struct MountAttempt {
+    udid: String,
+    generation: u64,
+    mounted: bool,
+}
+
+impl MountAttempt {
+    fn start(udid: String, generation: u64) -> MountAttempt {
+        connection::mounting();
+        MountAttempt {
+            udid,
+            generation,
+            mounted: false,
+        }
+    }
+
+    /// Whether `stop_auto_mounter` was called since the attempt started
+    fn stopped(&self) -> bool {
+        MOUNTER_GENERATION.load(Ordering::Relaxed) != self.generation
+    }
+
+    /// Records that the developer disk image is mounted, unless the mounter was stopped while
+    /// the attempt was in flight
+    fn mounted(&mut self) {
+        self.mounted = true;
+        if self.stopped() {
+            info!("Image mounter was stopped, not reporting the mount");
+            return;
+        }
+        DEVICES.set_mounted(Some(&self.udid), true);
+        connection::mount_finished(true);
+    }
+}
 
-/// Mount iOS's developer DMG
-pub fn start_auto_mounter(docs_path: String) {
+impl Drop for MountAttempt {
+    fn drop(&mut self) {
+        if !self.mounted && !self.stopped() {
+            connection::mount_finished(false);
+        }
+    }
+}
+
+/// Mount iOS's developer DMG on the device, or the first registered device if no UDID is given
+pub fn start_auto_mounter(docs_path: String, udid: Option<String>) {
     #[cfg(not(test))]
     let docs_path = docs_path[7..].to_string(); // remove the file:// prefix
     let dmg_docs_path = format!("{docs_path}/DMG");
     debug!("DMG path: {dmg_docs_path}");
+    let generation = MOUNTER_GENERATION.load(Ordering::Relaxed);
 
     // This will take a while, especially if the muxer is still waking up
     // Let's move to a new thread
@@ -45,47 +103,32 @@ pub fn start_auto_mounter(docs_path: String) {
             // Create the DMG folder if it doesn't exist
             std::fs::create_dir_all(&dmg_docs_path).unwrap();
 
-
-
             loop {
                 // Sleep in between failed attempts
                 std::thread::sleep(std::time::Duration::from_secs(5));
+                if MOUNTER_GENERATION.load(Ordering::Relaxed) != generation {
+                    info!("Image mounter was stopped");
+                    return;
+                }
                 info!("Trying to mount dev image");
 
                 // Fetch the device
-                let device = match fetch_first_device() {
+                let device = match fetch_device(udid.as_deref()) {
                     Ok(d) => d,
                     _ => continue,
                 };
-
-                let ld_client = match device.new_lockdownd_client("minimuxer") {
-                    Ok(l) => l,
-                    Err(e) => {
-                        error!("Failed to connect to lockdown: {e:?}");
-                        continue;
-                    }
-                };
-
-                let product_version = match ld_client.get_value("ProductVersion", "") {
-                    Ok(p) => p,
-                    Err(e) => {
-                        error!("Failed to get product version from lockdown: {e:?}");
-                        continue;
-                    }
+                let registered = match DEVICES.get(udid.as_deref()) {
+                    Some(d) => d.udid,
+                    None => continue,
                 };
+                let mut attempt = MountAttempt::start(registered, generation);
 
-                let product_version = if let Some(product_version) = product_version
-                    .get_string_val()
-                    .ok()
-                    .and_then(|x| x.split('.').collect::<Vec<&str>>()[0].parse::<u8>().ok())
-                {
-                    product_version
-                } else {
-                    error!("Failed to get product version from plist");
-                    continue;
+                let capabilities = match device_capabilities(udid.clone()) {
+                    Ok(c) => c,
+                    Err(_) => continue,
                 };
 
-                if product_version < 17 {
+                if capabilities.developer_disk_image == DdiMethod::Legacy {
                     // Start an image mounter service
                     let mim = match device.new_mobile_image_mounter("sidestore-image-reeeee") {
                         Ok(m) => m,
@@ -107,7 +150,7 @@ pub fn start_auto_mounter(docs_path: String) {
                         Ok(a) => match a.array_get_size() {
                             Ok(n) => {
                                 if n > 0 {
-                                    DMG_MOUNTED.store(true, Ordering::Relaxed);
+                                    attempt.mounted();
                                     info!("Developer disk image already mounted");
                                     break;
                                 }
@@ -123,22 +166,7 @@ pub fn start_auto_mounter(docs_path: String) {
                         }
                     }
 
-                    let lockdown_client = match device.new_lockdownd_client("sidestore-lockdown-reeeee")
-                    {
-                        Ok(l) => l,
-                        Err(e) => {
-                            error!("Unable to create lockdown client: {:?}", e);
-                            continue;
-                        }
-                    };
-
-                    let ios_version = match lockdown_client.get_value("ProductVersion", "") {
-                        Ok(ios_version) => ios_version.get_string_val().unwrap(),
-                        Err(e) => {
-                            error!("Error getting iOS version: {:?}", e);
-                            continue;
-                        }
-                    };
+                    let ios_version = capabilities.version.to_string();
 
                     // Determine if we already have the DMG downloaded
                     let path =
@@ -274,7 +302,7 @@ pub fn start_auto_mounter(docs_path: String) {
 
                     match mim.mount_image(&path, "Developer", format!("{path}.signature")) {
                         Ok(_) => {
-                            DMG_MOUNTED.store(true, Ordering::Relaxed);
+                            attempt.mounted();
                             info!("Successfully mounted the image");
                             break;
                         }
@@ -285,6 +313,7 @@ pub fn start_auto_mounter(docs_path: String) {
                     }
                 } else {
                     let dmg_docs_path = dmg_docs_path.clone(); 
+                    let udid = udid.clone();
                     if let Err(e) = RUNTIME.block_on(async move {
                         // Make sure everything is downloaded
                         let dir = PathBuf::from(dmg_docs_path);
@@ -306,40 +335,8 @@ pub fn start_auto_mounter(docs_path: String) {
                             }
                         }
 
-                        info!("Files downloaded, getting device from muxer");
-
-                        let mut uc = UsbmuxdConnection::new(
-                            Box::new(
-                                match tokio::net::TcpStream::connect("127.0.0.1:27015").await {
-                                    Ok(u) => u,
-                                    Err(_) => {
-                                        return Err(Errors::NoConnection);
-                                    }
-                                },
-                            ),
-                            0,
-                        );
-
-                        let dev = match uc
-                            .get_devices()
-                            .await
-                            .ok()
-                            .and_then(|x| x.into_iter().next())
-                        {
-                            Some(d) => d.to_provider(idevice::usbmuxd::UsbmuxdAddr::TcpSocket(std::net::SocketAddr::V4(
-                        SocketAddrV4::from_str("127.0.0.1:27015").unwrap(),
-                    )), 0, "asdf"),
-                            None => {
-                                return Err(Errors::NoConnection);
-                            }
-                        };
-
-                        info!("Creating provider from usbmuxd device");
-                        let provider = TcpProvider {
-                            addr: std::net::IpAddr::V4(Ipv4Addr::from_str("10.7.0.1").unwrap()),
-                            pairing_file: dev.get_pairing_file().await.unwrap(),
-                            label: "minimuxer".to_string(),
-                        };
+                        info!("Files downloaded, creating provider for the device");
+                        let provider = fetch_provider(udid.as_deref(), "minimuxer")?;
 
                         info!("Connecting to lockdown for UCID");
                         let mut lockdown_client = match LockdownClient::connect(&provider)
@@ -356,7 +353,7 @@ pub fn start_auto_mounter(docs_path: String) {
                             Ok(u) => u,
                             Err(_) => {
                                 if let Err(e) = lockdown_client
-                                    .start_session(&provider.get_pairing_file().await.unwrap())
+                                    .start_session(&provider.pairing_file)
                                     .await {
                                         error!("Failed to start session: {e:?}");
                                         return Err(Errors::CreateLockdown);
@@ -453,6 +450,7 @@ pub fn start_auto_mounter(docs_path: String) {
                         error!("Failed to mount personalized DDI: {e:?}");
                         continue;
                     } else {
+                        attempt.mounted();
                         break;
                     }
                 }
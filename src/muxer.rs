@@ -1,15 +1,42 @@
 // Jackson Coxson
 
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{Read, Write};
-use std::net::{IpAddr, Ipv4Addr, SocketAddrV4, TcpListener};
-use std::str::FromStr;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use log::{error, info, trace, warn};
+use log::{debug, error, info, trace, warn};
 use plist::{Dictionary, Value};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::runtime::RuntimeFlavor;
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
 
-use crate::{heartbeat::start_beat, plist_to_bytes, raw_packet::RawPacket, Errors};
+use crate::{
+    config::{current_config, ListenAddress, ResponseFormat},
+    connection,
+    heartbeat::{start_beat, stop_beats},
+    mounter::stop_auto_mounter,
+    pair_records::{shared_store, PairRecordStore},
+    pairing_file::{
+        read_pairing_file, stop_watching_pairing_files, watch_pairing_file, PairingFile,
+    },
+    raw_packet::{
+        read_frame, write_packet, Encoding, PlistFormat, RawPacket, BINARY_VERSION, MAX_FRAME_SIZE,
+        PLIST_VERSION,
+    },
+    registry::{DeviceEvent, DeviceRegistry, RegisteredDevice, DEVICES},
+    RustyPlistConversion, RUNTIME,
+};
 
 #[swift_bridge::bridge]
 mod ffi {
@@ -23,124 +50,659 @@ mod ffi {
             log_path: String,
             is_console_logging_enabled: bool,
         ) -> Result<(), Errors>;
+        fn start_with_pairing_file_path(
+            pairing_file_path: String,
+            log_path: String,
+            is_console_logging_enabled: bool,
+        ) -> Result<(), Errors>;
+        fn stop();
         fn target_minimuxer_address();
     }
 }
 
-const LISTEN_PORT: u16 = 27015;
+/// usbmuxd result numbers sent back in `Result` messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResultCode {
+    Ok = 0,
+    BadCommand = 1,
+    BadDevice = 2,
+    ConnectionRefused = 3,
+    BadVersion = 6,
+}
+
+/// State shared by every client connection
+pub struct MuxerState {
+    devices: Arc<DeviceRegistry>,
+    pair_records: Arc<PairRecordStore>,
+    clients: Mutex<HashMap<u64, ClientInfo>>,
+    next_client_id: AtomicU64,
+}
 
-pub fn listen(pairing_file: Dictionary) {
-    std::thread::Builder::new()
-        .name("muxer".to_string())
-        .spawn(move || {
-            // Create the listener
-            let mut listener = TcpListener::bind(SocketAddrV4::new(
-                Ipv4Addr::from_str("127.0.0.1").unwrap(),
-                LISTEN_PORT,
-            ))
-            .unwrap();
-            let mut retries = 0;
-            info!("Starting listener");
-            loop {
-                // Listen for requests
-                let (mut stream, _) = match listener.accept() {
-                    Ok(s) => s,
-                    Err(_) => {
-                        retries += 1;
-                        std::thread::sleep(std::time::Duration::from_millis(5));
-
-                        if retries < 50 {
-                            continue;
-                        } else {
-                            // Rebind
-                            warn!("minimuxer is rebinding to the muxer socket!!");
-                            std::mem::drop(listener);
-                            loop {
-                                listener = match TcpListener::bind(SocketAddrV4::new(
-                                    Ipv4Addr::from_str("127.0.0.1").unwrap(),
-                                    LISTEN_PORT,
-                                )) {
-                                    Ok(l) => l,
-                                    Err(_) => {
-                                        std::thread::sleep(std::time::Duration::from_millis(50));
-                                        continue;
-                                    }
-                                };
-                                break;
-                            }
-                            info!("minimuxer has bound successfully");
-                            retries = 0;
-
-                            continue;
-                        }
-                    }
-                };
-                retries = 0;
-
-                // Read the packet
-                let mut buf = [0u8; 0xfff];
-                let mut size = match stream.read(&mut buf) {
-                    Ok(s) => s,
-                    Err(e) => {
-                        trace!("read error: {e:?}");
+impl MuxerState {
+    pub fn new(devices: Arc<DeviceRegistry>, pair_records: Arc<PairRecordStore>) -> MuxerState {
+        MuxerState {
+            devices,
+            pair_records,
+            clients: Mutex::new(HashMap::new()),
+            next_client_id: AtomicU64::new(1),
+        }
+    }
+}
+
+/// What a client told us about itself, reported by `ListListeners`
+#[derive(Default)]
+struct ClientInfo {
+    prog_name: String,
+    bundle_id: String,
+    lib_usbmux_version: u64,
+}
+
+/// Keeps a client in `MuxerState::clients` for as long as its connection is open
+struct ClientRegistration {
+    id: u64,
+    state: Arc<MuxerState>,
+}
+
+impl ClientRegistration {
+    fn new(state: Arc<MuxerState>) -> ClientRegistration {
+        let id = state.next_client_id.fetch_add(1, Ordering::Relaxed);
+        state
+            .clients
+            .lock()
+            .unwrap()
+            .insert(id, ClientInfo::default());
+        ClientRegistration { id, state }
+    }
+
+    /// Updates the client's info from the ProgName, BundleID and kLibUSBMuxVersion that
+    /// libusbmuxd adds to every request
+    fn update(&self, request: &Dictionary) {
+        let mut clients = self.state.clients.lock().unwrap();
+        let info = match clients.get_mut(&self.id) {
+            Some(i) => i,
+            None => return,
+        };
+        if let Some(p) = request.get("ProgName").and_then(|p| p.as_string()) {
+            info.prog_name = p.to_string();
+        }
+        if let Some(b) = request.get("BundleID").and_then(|b| b.as_string()) {
+            info.bundle_id = b.to_string();
+        }
+        if let Some(v) = request
+            .get("kLibUSBMuxVersion")
+            .and_then(|v| v.as_unsigned_integer())
+        {
+            info.lib_usbmux_version = v;
+        }
+    }
+}
+
+impl Drop for ClientRegistration {
+    fn drop(&mut self) {
+        self.state.clients.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// The running listener task and the channel used to tell it (and its clients) to stop
+struct Listener {
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+static LISTENER: Mutex<Option<Listener>> = Mutex::new(None);
+
+/// Starts the muxer server for the registered devices on the runtime, replacing any server that
+/// is already running
+pub fn listen() {
+    stop_listener();
+
+    let config = current_config();
+    let state = Arc::new(MuxerState::new(
+        DEVICES.clone(),
+        shared_store(config.pair_record_directory.clone()),
+    ));
+    let addr = config.listen_on();
+    let (shutdown, shutdown_rx) = watch::channel(false);
+    let task = RUNTIME.spawn(serve(addr, state, shutdown_rx));
+
+    *LISTENER.lock().unwrap() = Some(Listener { shutdown, task });
+}
+
+/// Whether the muxer server is running
+pub fn listening() -> bool {
+    LISTENER.lock().unwrap().is_some()
+}
+
+/// Stops the muxer server and every client connection, then waits for the socket to be released.
+/// Can be called from async code.
+pub fn stop_listener() {
+    let listener = match LISTENER.lock().unwrap().take() {
+        Some(l) => l,
+        None => return,
+    };
+
+    info!("Stopping listener");
+    let _ = listener.shutdown.send(true);
+    let result = match tokio::runtime::Handle::try_current() {
+        // block_on panics on a runtime thread (when set_config is called from async code, for
+        // example), so take the thread out of the runtime while waiting
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| RUNTIME.block_on(listener.task))
+        }
+        // The thread can't be taken out of a current thread runtime, so let the task finish on
+        // its own
+        Ok(_) => {
+            warn!("Not waiting for the listener to stop on a current thread runtime");
+            return;
+        }
+        Err(_) => RUNTIME.block_on(listener.task),
+    };
+    if let Err(e) = result {
+        error!("Listener task failed: {e:?}");
+    }
+}
+
+/// Accepts clients until shutdown, spawning a task for each one
+pub async fn serve(
+    addr: ListenAddress,
+    state: Arc<MuxerState>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut listener = match bind(&addr, &mut shutdown).await {
+        Some(l) => l,
+        None => return,
+    };
+    info!("Starting listener");
+
+    let mut errors = 0;
+    loop {
+        // Listen for requests
+        let stream = tokio::select! {
+            _ = shutdown.changed() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(s) => s,
+                Err(e) => {
+                    errors += 1;
+                    warn!("Failed to accept client: {e:?}");
+                    if errors < 50 {
+                        tokio::time::sleep(Duration::from_millis(5)).await;
                         continue;
                     }
-                };
-
-                // Detect if only header was sent
-                if size == 16 {
-                    let mut buf2 = [0u8; 0xfff];
-                    let new_size = match stream.read(&mut buf2) {
-                        Ok(s) => s,
-                        Err(_) => continue,
+
+                    warn!("minimuxer is rebinding to the muxer socket!!");
+                    std::mem::drop(listener);
+                    listener = match bind(&addr, &mut shutdown).await {
+                        Some(l) => l,
+                        None => break,
                     };
-                    let mut i = size;
-                    for o in buf2 {
-                        if i == buf.len() - 1 {
-                            continue;
-                        }
-                        buf[i] = o;
-                        i += 1;
-                    }
-                    size += new_size;
+                    info!("minimuxer has bound successfully");
+                    errors = 0;
+                    continue;
                 }
+            },
+        };
+        errors = 0;
 
-                let packet: RawPacket = buf[..size].try_into().unwrap();
+        match stream {
+            Client::Tcp(s) => tokio::spawn(handle_client(s, state.clone(), shutdown.clone())),
+            #[cfg(unix)]
+            Client::Unix(s) => tokio::spawn(handle_client(s, state.clone(), shutdown.clone())),
+        };
+    }
 
-                // Handle the request
-                let response = match handle_packet(&packet, &pairing_file) {
-                    Ok(res) => res,
-                    Err(e) => {
-                        trace!("handle_packet error: {e:?}");
-                        continue;
-                    }
-                };
+    info!("Listener stopped");
+}
+
+/// A bound muxer socket
+enum MuxerListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixSocket),
+}
+
+/// A client connection accepted by a `MuxerListener`
+enum Client {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl MuxerListener {
+    async fn accept(&self) -> io::Result<Client> {
+        match self {
+            MuxerListener::Tcp(l) => l.accept().await.map(|(s, _)| Client::Tcp(s)),
+            #[cfg(unix)]
+            MuxerListener::Unix(l) => l.listener.accept().await.map(|(s, _)| Client::Unix(s)),
+        }
+    }
+}
+
+/// Binds to `addr`, retrying until it succeeds or shutdown is requested
+async fn bind(addr: &ListenAddress, shutdown: &mut watch::Receiver<bool>) -> Option<MuxerListener> {
+    loop {
+        let bound = match addr {
+            ListenAddress::Tcp(a) => TcpListener::bind(a).await.map(MuxerListener::Tcp),
+            #[cfg(unix)]
+            ListenAddress::Unix(path) => bind_unix(path).map(MuxerListener::Unix),
+            #[cfg(not(unix))]
+            ListenAddress::Unix(_) => {
+                error!("Unix domain sockets aren't supported on this platform");
+                return None;
+            }
+        };
+        match bound {
+            Ok(l) => return Some(l),
+            Err(e) => {
+                warn!("Failed to bind to {addr}: {e:?}");
+                tokio::select! {
+                    _ = shutdown.changed() => return None,
+                    _ = tokio::time::sleep(Duration::from_millis(50)) => {}
+                }
+            }
+        }
+    }
+}
+
+/// Answers requests from the client until it disconnects or shutdown is requested. If the
+/// client asked to `Connect`, the stream becomes a relay to the device.
+pub async fn handle_client<S>(
+    mut stream: S,
+    state: Arc<MuxerState>,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let registration = ClientRegistration::new(state.clone());
+
+    loop {
+        // Read the packet
+        let frame = tokio::select! {
+            _ = shutdown.changed() => return,
+            read = read_frame(&mut stream, MAX_FRAME_SIZE) => match read {
+                Ok(Some(f)) => f,
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("read error: {e}");
+                    return;
+                }
+            },
+        };
+
+        let tag = frame.header.tag;
+        // Reply in whatever version and plist format the client used, or in plist if we don't
+        // know the version
+        let format = match current_config().response_format {
+            ResponseFormat::MatchRequest => PlistFormat::detect(&frame.body),
+            ResponseFormat::Xml => PlistFormat::Xml,
+            ResponseFormat::Binary => PlistFormat::Binary,
+        };
+        let (packet, version) = match frame.header.version {
+            v @ (BINARY_VERSION | PLIST_VERSION) => (
+                frame.decode().map_err(|e| {
+                    warn!("Failed to decode packet: {e}");
+                    HandlePacketError::BadPacket
+                }),
+                v,
+            ),
+            v => (Err(HandlePacketError::BadVersion(v)), PLIST_VERSION),
+        };
+        let encoding = Encoding { version, format };
+
+        // Handle the request
+        let response = match packet.and_then(|packet| {
+            if let Some(request) = packet.plist.as_dictionary() {
+                registration.update(request);
+            }
+            handle_packet(&packet, &state)
+        }) {
+            Ok(PacketAction::Reply(res)) => res,
+            Ok(PacketAction::Listen) => {
+                subscribe(stream, &state, encoding, tag, shutdown).await;
+                return;
+            }
+            Ok(PacketAction::Connect(device)) => {
+                connect(stream, device, encoding, tag, shutdown).await;
+                return;
+            }
+            Err(e) => {
+                e.log();
+                result_message(e.result_code())
+            }
+        };
+
+        let response = RawPacket::with_encoding(response, encoding, tag);
+        if let Err(e) = write_packet(&mut stream, &response).await {
+            trace!("write error: {e:?}");
+            return;
+        }
+    }
+}
+
+/// Binds a Unix domain socket that anyone can connect to, like usbmuxd's. A socket file left
+/// behind by a previous run is replaced, but one something is still listening on, or a path that
+/// isn't a socket, is left alone.
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> io::Result<UnixSocket> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+
+    match std::fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("something is already listening on {}", path.display()),
+                ));
+            }
+            std::fs::remove_file(path)?;
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} exists and isn't a socket", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o666))?;
+    let metadata = std::fs::symlink_metadata(path)?;
+    Ok(UnixSocket {
+        listener,
+        path: path.to_path_buf(),
+        dev: metadata.dev(),
+        ino: metadata.ino(),
+    })
+}
+
+/// A bound Unix listener. Its socket file is removed when it's dropped, unless something else
+/// has been bound to the path since.
+#[cfg(unix)]
+struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    dev: u64,
+    ino: u64,
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        use std::os::unix::fs::MetadataExt;
 
-                let to_return: Vec<u8> = RawPacket::new(response, 1, 8, packet.tag).into();
-                if let Err(e) = stream.write_all(&to_return) {
+        match std::fs::symlink_metadata(&self.path) {
+            Ok(m) if m.dev() == self.dev && m.ino() == self.ino => {
+                let _ = std::fs::remove_file(&self.path);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Connects to `device` and relays bytes between it and the client until either side hangs up
+async fn connect<S>(
+    mut client: S,
+    device: SocketAddr,
+    encoding: Encoding,
+    tag: u32,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut device_stream =
+        match tokio::time::timeout(current_config().connect_timeout, TcpStream::connect(device))
+            .await
+        {
+            Ok(Ok(d)) => d,
+            Ok(Err(e)) => {
+                warn!("Failed to connect to {device}: {e:?}");
+                if let Err(e) = client
+                    .write_all(&result_packet(ResultCode::ConnectionRefused, encoding, tag))
+                    .await
+                {
+                    trace!("write error: {e:?}");
+                }
+                return;
+            }
+            Err(_) => {
+                warn!("Timed out connecting to {device}");
+                if let Err(e) = client
+                    .write_all(&result_packet(ResultCode::ConnectionRefused, encoding, tag))
+                    .await
+                {
                     trace!("write error: {e:?}");
-                    continue;
                 }
+                return;
+            }
+        };
+
+    if let Err(e) = client
+        .write_all(&result_packet(ResultCode::Ok, encoding, tag))
+        .await
+    {
+        trace!("write error: {e:?}");
+        return;
+    }
+    info!("Relaying connection to {device}");
+
+    tokio::select! {
+        _ = shutdown.changed() => {}
+        relayed = tokio::io::copy_bidirectional(&mut client, &mut device_stream) => {
+            if let Err(e) = relayed {
+                trace!("relay ended: {e:?}");
+            }
+        }
+    }
+    debug!("Relay to {device} closed");
+}
+
+/// Keeps a `Listen` connection open, sending the attached devices and then every attach/detach
+/// until the client hangs up
+async fn subscribe<S>(
+    mut client: S,
+    state: &MuxerState,
+    encoding: Encoding,
+    tag: u32,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Subscribe before reporting the current state so no event is missed in between
+    let mut events = state.devices.subscribe();
+
+    if let Err(e) = client
+        .write_all(&result_packet(ResultCode::Ok, encoding, tag))
+        .await
+    {
+        trace!("write error: {e:?}");
+        return;
+    }
+
+    // The devices this client has been told are attached
+    let mut attached = HashSet::new();
+    if let Err(e) = sync_devices(&mut client, state, encoding, &mut attached).await {
+        trace!("write error: {e:?}");
+        return;
+    }
+    info!("Client subscribed to device events");
+
+    loop {
+        let mut buf = [0u8; 1];
+        let event = tokio::select! {
+            _ = shutdown.changed() => break,
+            // Subscribers don't send anything else, so any read means the client is done
+            _ = client.read(&mut buf) => break,
+            event = events.recv() => event,
+        };
+
+        let sent = match event {
+            Ok(e) => send_device_event(&mut client, state, encoding, &mut attached, e).await,
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                sync_devices(&mut client, state, encoding, &mut attached).await
             }
-        })
-        .unwrap();
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if let Err(e) = sent {
+            trace!("write error: {e:?}");
+            break;
+        }
+    }
+    debug!("Device event subscriber disconnected");
 }
 
+/// Sends whatever `Attached` and `Detached` messages are needed to bring the client in line with
+/// the registry
+async fn sync_devices<S>(
+    client: &mut S,
+    state: &MuxerState,
+    encoding: Encoding,
+    attached: &mut HashSet<u64>,
+) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let devices = state.devices.all();
+    let gone: Vec<u64> = attached
+        .iter()
+        .filter(|id| !devices.iter().any(|d| d.device_id == **id && d.attached))
+        .copied()
+        .collect();
+    for device_id in gone {
+        attached.remove(&device_id);
+        write_event(client, encoding, detached_message(device_id)).await?;
+    }
+    for device in devices {
+        if device.attached && attached.insert(device.device_id) {
+            write_event(client, encoding, attached_message(&device)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Forwards a registry event to the client if it changes what the client knows
+async fn send_device_event<S>(
+    client: &mut S,
+    state: &MuxerState,
+    encoding: Encoding,
+    attached: &mut HashSet<u64>,
+    event: DeviceEvent,
+) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    if !event.attached {
+        if attached.remove(&event.device_id) {
+            write_event(client, encoding, detached_message(event.device_id)).await?;
+        }
+        return Ok(());
+    }
+
+    // The device may have been removed since the event was sent
+    let device = match state.devices.get(Some(&event.udid)) {
+        Some(d) => d,
+        None => return Ok(()),
+    };
+    if attached.insert(device.device_id) {
+        write_event(client, encoding, attached_message(&device)).await?;
+    }
+    Ok(())
+}
+
+/// Sends an `Attached` or `Detached` message. Like usbmuxd, events aren't replies so they use tag 0.
+async fn write_event<S>(
+    client: &mut S,
+    encoding: Encoding,
+    message: Dictionary,
+) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    write_packet(
+        client,
+        &RawPacket::with_encoding(message.into(), encoding, 0),
+    )
+    .await
+}
+
+/// Builds a usbmuxd `Result` message with the given result number
+fn result_message(code: ResultCode) -> Value {
+    let mut output = Dictionary::new();
+    output.insert("MessageType".to_string(), "Result".into());
+    output.insert("Number".to_string(), (code as u64).into());
+    output.into()
+}
+
+fn result_packet(code: ResultCode, encoding: Encoding, tag: u32) -> Vec<u8> {
+    RawPacket::with_encoding(result_message(code), encoding, tag).encode()
+}
+
+/// Why a request failed. Every error is answered with a `Result` carrying its `result_code`.
 #[derive(Debug)]
 enum HandlePacketError {
+    /// The packet or one of its fields couldn't be parsed
     BadPacket,
-    UnknownMessageType,
-    BadPairingFile,
+    BadVersion(u32),
+    UnknownMessageType(String),
+    /// There's no pairing file to answer from
+    NoDevices,
+    UnknownDevice(u64),
+    NoPairRecord(String),
+    /// The record is a registered device's pairing file, which can't be saved over or deleted
+    RegisteredPairRecord(String),
+    PairRecordStore(std::io::Error),
+}
+
+impl HandlePacketError {
+    fn result_code(&self) -> ResultCode {
+        match self {
+            HandlePacketError::BadPacket
+            | HandlePacketError::UnknownMessageType(_)
+            | HandlePacketError::RegisteredPairRecord(_)
+            | HandlePacketError::PairRecordStore(_) => ResultCode::BadCommand,
+            HandlePacketError::BadVersion(_) => ResultCode::BadVersion,
+            HandlePacketError::NoDevices
+            | HandlePacketError::UnknownDevice(_)
+            | HandlePacketError::NoPairRecord(_) => ResultCode::BadDevice,
+        }
+    }
+
+    /// Client mistakes are warnings, problems on our end are errors
+    fn log(&self) {
+        match self {
+            HandlePacketError::BadPacket => warn!("Rejecting malformed request"),
+            HandlePacketError::BadVersion(v) => warn!("Rejecting request for protocol version {v}"),
+            HandlePacketError::UnknownMessageType(t) => warn!("Unsupported message type {t}"),
+            HandlePacketError::NoDevices => warn!("No devices are registered"),
+            HandlePacketError::UnknownDevice(id) => warn!("Request for unknown device {id}"),
+            HandlePacketError::NoPairRecord(id) => warn!("No pair record for {id}"),
+            HandlePacketError::RegisteredPairRecord(id) => warn!(
+                "Not changing the pair record for {id}, it's a registered device's pairing file"
+            ),
+            HandlePacketError::PairRecordStore(e) => error!("Pair record store failed: {e:?}"),
+        }
+    }
+}
+
+/// What should be done with the client after a packet has been handled
+enum PacketAction {
+    /// Send the plist back to the client
+    Reply(Value),
+    /// Connect the client to this address
+    Connect(SocketAddr),
+    /// Keep the connection open and send the client device events
+    Listen,
 }
 
 fn handle_packet(
     packet: &RawPacket,
-    pairing_file: &Dictionary,
-) -> Result<Value, HandlePacketError> {
-    let message_type = packet
+    state: &MuxerState,
+) -> Result<PacketAction, HandlePacketError> {
+    let request = packet
         .plist
         .as_dictionary()
-        .ok_or(HandlePacketError::BadPacket)?
+        .ok_or(HandlePacketError::BadPacket)?;
+    let message_type = request
         .get("MessageType")
         .ok_or(HandlePacketError::BadPacket)?
         .as_string()
@@ -148,72 +710,224 @@ fn handle_packet(
     trace!("Handling {message_type}");
 
     match message_type {
-        "ListDevices" | "Listen" => {
-            // Get the device UDID from the pairing file
-            let udid = pairing_file
-                .get("UDID")
-                .ok_or(HandlePacketError::BadPairingFile)?
-                .as_string()
-                .ok_or(HandlePacketError::BadPairingFile)?;
-
-            /*
-            {
-                DeviceList: [
-                    {
-                        DeviceID: 420
-                        MessageType: Attached
-                        Properties: {
-                            ConnetionType: "Network"
-                            DeviceID: 420
-                            EscapedFullServiceName: "yurmomlolllllll"
-                            InterfaceIndex: 69
-                            NetworkAddress: 10.7.0.1 as bytes
-                            SerialNumber: "<udid>""
-                        }
-                    }
-                ]
+        "ListDevices" => {
+            let devices: Vec<Value> = state
+                .devices
+                .all()
+                .iter()
+                .map(|d| attached_message(d).into())
+                .collect();
+
+            let mut output = Dictionary::new();
+            output.insert("DeviceList".to_string(), devices.into());
+            Ok(PacketAction::Reply(output.into()))
+        }
+        "Listen" => Ok(PacketAction::Listen),
+        "ReadPairRecord" => {
+            let id = request
+                .get("PairRecordID")
+                .and_then(|i| i.as_string())
+                .ok_or(HandlePacketError::BadPacket)?;
+            // Registered devices' pairing files take priority, so every client and minimuxer
+            // itself use the same one
+            let data = state
+                .devices
+                .get(Some(id))
+                .map(|d| d.pairing_file.to_xml())
+                .or_else(|| state.pair_records.get(id))
+                .ok_or_else(|| HandlePacketError::NoPairRecord(id.to_string()))?;
+
+            let mut output = Dictionary::new();
+            output.insert("PairRecordData".to_string(), Value::Data(data));
+            Ok(PacketAction::Reply(output.into()))
+        }
+        "SavePairRecord" => {
+            let id = request.get("PairRecordID").and_then(|i| i.as_string());
+            let data = request.get("PairRecordData").and_then(|d| d.as_data());
+            let (id, data) = match (id, data) {
+                // make sure we only ever hand out valid plists
+                (Some(i), Some(d)) if Value::from_bytes(d).is_ok() => (i, d),
+                _ => return Err(HandlePacketError::BadPacket),
+            };
+            // Read would never serve it, the registered pairing file wins
+            if state.devices.get(Some(id)).is_some() {
+                return Err(HandlePacketError::RegisteredPairRecord(id.to_string()));
             }
-            */
-
-            let mut properties = Dictionary::new();
-            properties.insert("ConnectionType".to_string(), "Network".into());
-            properties.insert("DeviceID".to_string(), 420.into());
-            properties.insert(
-                "EscapedFullServiceName".to_string(),
-                "yurmomlolllllll".into(),
-            );
-            properties.insert("InterfaceIndex".to_string(), 69.into());
-            properties.insert(
-                "NetworkAddress".to_string(),
-                Value::Data(
-                    convert_ip(IpAddr::V4(Ipv4Addr::from_str("10.7.0.1").unwrap())).to_vec(),
-                ),
-            );
-            properties.insert("SerialNumber".to_string(), udid.into());
 
-            let mut device = Dictionary::new();
-            device.insert("DeviceID".to_string(), 420.into());
-            device.insert("MessageType".to_string(), "Attached".into());
-            device.insert("Properties".to_string(), properties.into());
+            state
+                .pair_records
+                .save(id.to_string(), data.to_vec())
+                .map_err(HandlePacketError::PairRecordStore)?;
+            info!("Saved pair record for {id}");
+            Ok(PacketAction::Reply(result_message(ResultCode::Ok)))
+        }
+        "DeletePairRecord" => {
+            let id = request
+                .get("PairRecordID")
+                .and_then(|i| i.as_string())
+                .ok_or(HandlePacketError::BadPacket)?;
+            // Read serves registered devices' pairing files, so deleting only a saved record
+            // wouldn't remove the device's. The device has to be removed instead.
+            if state.devices.get(Some(id)).is_some() {
+                return Err(HandlePacketError::RegisteredPairRecord(id.to_string()));
+            }
+
+            if !state
+                .pair_records
+                .delete(id)
+                .map_err(HandlePacketError::PairRecordStore)?
+            {
+                return Err(HandlePacketError::NoPairRecord(id.to_string()));
+            }
+            info!("Deleted pair record for {id}");
+            Ok(PacketAction::Reply(result_message(ResultCode::Ok)))
+        }
+        "ReadBUID" => {
+            // Every pairing file made by the same host has the same SystemBUID
+            let buid = state
+                .devices
+                .get(None)
+                .map(|d| d.pairing_file.system_buid)
+                .ok_or(HandlePacketError::NoDevices)?;
 
             let mut output = Dictionary::new();
-            output.insert("DeviceList".to_string(), vec![device.into()].into());
-            Ok(output.into())
+            output.insert("BUID".to_string(), buid.into());
+            Ok(PacketAction::Reply(output.into()))
         }
-        "ReadPairRecord" => {
+        "ListListeners" => {
+            let clients = state.clients.lock().unwrap();
+            let mut ids: Vec<&u64> = clients.keys().collect();
+            ids.sort();
+
+            let listeners: Vec<Value> = ids
+                .into_iter()
+                .map(|id| {
+                    let info = &clients[id];
+                    let mut listener = Dictionary::new();
+                    listener.insert("Blacklisted".to_string(), false.into());
+                    listener.insert("BundleID".to_string(), info.bundle_id.clone().into());
+                    listener.insert("ConnType".to_string(), 0.into());
+                    listener.insert("ID String".to_string(), id.to_string().into());
+                    listener.insert("ProgName".to_string(), info.prog_name.clone().into());
+                    listener.insert(
+                        "kLibUSBMuxVersion".to_string(),
+                        info.lib_usbmux_version.into(),
+                    );
+                    listener.into()
+                })
+                .collect();
+
             let mut output = Dictionary::new();
-            output.insert(
-                "PairRecordData".to_string(),
-                Value::Data(plist_to_bytes(pairing_file)),
-            );
-            Ok(output.into())
+            output.insert("ListenerList".to_string(), listeners.into());
+            Ok(PacketAction::Reply(output.into()))
+        }
+        "Connect" => {
+            let device_id = request
+                .get("DeviceID")
+                .and_then(|d| d.as_unsigned_integer())
+                .ok_or(HandlePacketError::BadPacket)?;
+            let device = state
+                .devices
+                .get_by_device_id(device_id)
+                .ok_or(HandlePacketError::UnknownDevice(device_id))?;
+
+            // libusbmuxd sends the port in network byte order
+            let port = request
+                .get("PortNumber")
+                .and_then(|p| p.as_unsigned_integer())
+                .and_then(|p| u16::try_from(p).ok())
+                .ok_or(HandlePacketError::BadPacket)?;
+            Ok(PacketAction::Connect(SocketAddr::new(
+                device.address,
+                u16::from_be(port),
+            )))
         }
         // DEVELOPER NOTE: if you are getting UnknownMessageType errors, the best way to implement a message type is to search for it (for example ReadBUID) in the libimobiledevice org: https://github.com/search?q=org%3Alibimobiledevice+ReadBUID&type=code
         // Once you find how usbmuxd sends the message (or how libusbmuxd receives the message), you can reimplement it in this function.
-        _ => Err(HandlePacketError::UnknownMessageType),
+        _ => Err(HandlePacketError::UnknownMessageType(
+            message_type.to_string(),
+        )),
     }
 }
 
+/// Builds the `Attached` message describing the device, used in both `DeviceList` and for
+/// `Listen` subscribers
+fn attached_message(device: &RegisteredDevice) -> Dictionary {
+    /*
+    {
+        DeviceID: <derived from the udid>
+        MessageType: Attached
+        Properties: {
+            ConnectionType: "Network"
+            DeviceID: <derived from the udid>
+            EscapedFullServiceName: "<mac>@<link-local address>._apple-mobdev2._tcp.local."
+            NetworkAddress: <device address as a sockaddr>
+            SerialNumber: "<udid>"
+        }
+    }
+    */
+
+    let mut properties = Dictionary::new();
+    properties.insert("ConnectionType".to_string(), "Network".into());
+    properties.insert("DeviceID".to_string(), device.device_id.into());
+    // Older pairing files don't have the MAC address, so leave the service name out
+    match device
+        .pairing_file
+        .wifi_mac_address
+        .as_deref()
+        .and_then(service_name)
+    {
+        Some(name) => {
+            properties.insert("EscapedFullServiceName".to_string(), name.into());
+        }
+        None => debug!("Pairing file has no usable WiFiMACAddress"),
+    }
+    properties.insert(
+        "NetworkAddress".to_string(),
+        Value::Data(convert_ip(device.address).to_vec()),
+    );
+    properties.insert("SerialNumber".to_string(), device.udid.clone().into());
+
+    let mut message = Dictionary::new();
+    message.insert("DeviceID".to_string(), device.device_id.into());
+    message.insert("MessageType".to_string(), "Attached".into());
+    message.insert("Properties".to_string(), properties.into());
+    message
+}
+
+/// Builds the name the device advertises itself under over Bonjour for wireless syncing,
+/// `<mac>@<link-local address>._apple-mobdev2._tcp.local.`. The link-local address is the one
+/// the device derives from its MAC address (EUI-64).
+fn service_name(mac: &str) -> Option<String> {
+    let mac: Vec<u8> = mac
+        .split(':')
+        .map(|b| u8::from_str_radix(b, 16))
+        .collect::<Result<_, _>>()
+        .ok()?;
+    let mac: [u8; 6] = mac.try_into().ok()?;
+
+    let link_local = Ipv6Addr::new(
+        0xfe80,
+        0,
+        0,
+        0,
+        u16::from_be_bytes([mac[0] ^ 0x02, mac[1]]),
+        u16::from_be_bytes([mac[2], 0xff]),
+        u16::from_be_bytes([0xfe, mac[3]]),
+        u16::from_be_bytes([mac[4], mac[5]]),
+    );
+    let mac = mac.map(|b| format!("{b:02x}")).join(":");
+    Some(format!("{mac}@{link_local}._apple-mobdev2._tcp.local."))
+}
+
+/// Builds the `Detached` message sent to `Listen` subscribers when the device goes away
+fn detached_message(device_id: u64) -> Dictionary {
+    let mut device = Dictionary::new();
+    device.insert("DeviceID".to_string(), device_id.into());
+    device.insert("MessageType".to_string(), "Detached".into());
+    device
+}
+
 fn convert_ip(ip: IpAddr) -> [u8; 152] {
     let mut data = [0u8; 152];
     match ip {
@@ -236,7 +950,8 @@ fn convert_ip(ip: IpAddr) -> [u8; 152] {
             data[4] = 0x00;
             data[5] = 0x00;
             data[6] = 0x00;
-            let mut i = 16;
+            // sin6_flowinfo comes before the address
+            let mut i = 8;
             for byte in ip_addr.octets() {
                 data[i] = byte;
                 i += 1;
@@ -261,6 +976,9 @@ pub static STARTED: AtomicBool = AtomicBool::new(false);
 #[cfg(test)]
 pub static STARTED: AtomicBool = AtomicBool::new(true); // minimuxer won't start in tests
 
+/// Set once the logger has been applied, which can't be undone
+static LOGGER_STARTED: AtomicBool = AtomicBool::new(false);
+
 /// Starts the muxer and heartbeat client
 /// # Arguments
 /// Pairing file contents as a string and log path as a string
@@ -273,91 +991,134 @@ pub fn startWithLogger(
     log_path: String,
     is_console_logging_enabled: bool,
 ) -> crate::Res<()> {
-    use fern::Dispatch;
-    use log::LevelFilter;
+    if STARTED.load(Ordering::Relaxed) {
+        info!("Already started minimuxer, skipping");
+        return Ok(());
+    }
+    init_logger(&log_path, is_console_logging_enabled);
 
-    let log_path = format!("{}/minimuxer.log", &log_path[7..]); // remove the file:// prefix
+    let pairing_file = PairingFile::from_bytes(pairing_file.as_bytes())?;
+    DEVICES.add(pairing_file, current_config().device_address);
 
+    launch();
+    Ok(())
+}
+
+/// Starts the muxer and heartbeat client with the pairing file at `pairing_file_path`. The file
+/// is watched, and when it changes (after re-pairing, for example) the new pairing file is
+/// validated and swapped in without restarting.
+pub fn start_with_pairing_file_path(
+    pairing_file_path: String,
+    log_path: String,
+    is_console_logging_enabled: bool,
+) -> crate::Res<()> {
     if STARTED.load(Ordering::Relaxed) {
         info!("Already started minimuxer, skipping");
         return Ok(());
-    } else if std::fs::remove_file(&log_path).is_ok() { // only remove log file on first startup
-    }
-
-    // the logger failing to initialize isn't a problem since it will only fail if it has already been initialized
-    let mut logger = Dispatch::new().format(|out, message, record| {
-        out.finish(format_args!(
-            "{} [{}] {}: {}",
-            chrono::Local::now().format("%X"),
-            record.level(),
-            record.target(),
-            message
-        ))
-    });
-
-    // conditionally enable stdout logging only if requested
-    if is_console_logging_enabled {
-        logger = logger.chain(
-            Dispatch::new()
-                .level(LevelFilter::Trace)
-                .level_for("plist_plus", LevelFilter::Off) // plist_plus spams logs
-                // crates that spam logs when signing
-                .level_for("goblin", LevelFilter::Off)
-                .level_for("reqwest", LevelFilter::Off)
-                .level_for("want", LevelFilter::Off)
-                .level_for("mio", LevelFilter::Off)
-                .level_for("hyper", LevelFilter::Off)
-                .level_for("tracing", LevelFilter::Off) // maybe we shouldn't do this?
-                .chain(std::io::stdout()),
-        );
     }
+    init_logger(&log_path, is_console_logging_enabled);
 
-    logger = logger.chain(
-        // minimuxer.log
-        Dispatch::new()
-            .level(LevelFilter::Off)
-            .level_for("minimuxer", LevelFilter::Info)
-            .level_for("rusty_libimobiledevice", LevelFilter::Error)
-            .level_for("idevice", LevelFilter::Debug)
-            .chain(File::create(&log_path).unwrap()),
+    // paths from Swift may be file URLs
+    let path = PathBuf::from(
+        pairing_file_path
+            .strip_prefix("file://")
+            .unwrap_or(&pairing_file_path),
     );
+    let pairing_file = read_pairing_file(&path)?;
+    let address = current_config().device_address;
+    let udid = DEVICES.add(pairing_file, address);
+    watch_pairing_file(DEVICES.clone(), path, address, udid);
 
-    // apply logger
-    if logger.apply().is_ok() {
-        info!("Logger initialized!!");
-    }
+    launch();
+    Ok(())
+}
+
+/// Sets up logging to `minimuxer.log` in `log_path` (a file URL) and optionally the console
+fn init_logger(log_path: &str, is_console_logging_enabled: bool) {
+    use fern::Dispatch;
+    use log::LevelFilter;
+
+    let log_path = format!("{}/minimuxer.log", &log_path[7..]); // remove the file:// prefix
+
+    // a logger can only be applied once, so starting again after `stop` keeps using the first one
+    if !LOGGER_STARTED.swap(true, Ordering::Relaxed) {
+        let _ = std::fs::remove_file(&log_path); // only remove log file on first startup
+
+        // the logger failing to initialize isn't a problem since it will only fail if it has already been initialized
+        let mut logger = Dispatch::new().format(|out, message, record| {
+            out.finish(format_args!(
+                "{} [{}] {}: {}",
+                chrono::Local::now().format("%X"),
+                record.level(),
+                record.target(),
+                message
+            ))
+        });
 
-    let pairing_file: Dictionary = match plist::from_bytes(pairing_file.as_bytes()) {
-        Ok(p) => p,
-        Err(e) => {
-            error!("Failed to convert pairing file to plist!! {e:?}");
-            return Err(Errors::PairingFile);
+        // conditionally enable stdout logging only if requested
+        if is_console_logging_enabled {
+            logger = logger.chain(
+                Dispatch::new()
+                    .level(LevelFilter::Trace)
+                    .level_for("plist_plus", LevelFilter::Off) // plist_plus spams logs
+                    // crates that spam logs when signing
+                    .level_for("goblin", LevelFilter::Off)
+                    .level_for("reqwest", LevelFilter::Off)
+                    .level_for("want", LevelFilter::Off)
+                    .level_for("mio", LevelFilter::Off)
+                    .level_for("hyper", LevelFilter::Off)
+                    .level_for("tracing", LevelFilter::Off) // maybe we shouldn't do this?
+                    .chain(std::io::stdout()),
+            );
         }
-    };
 
-    match pairing_file.get("UDID") {
-        Some(u) => match u.as_string() {
-            Some(_) => {}
-            None => {
-                error!("Couldn't convert UDID to string");
-                return Err(Errors::PairingFile);
-            }
-        },
-        None => {
-            error!("Couldn't get UDID");
-            return Err(Errors::PairingFile);
+        logger = logger.chain(
+            // minimuxer.log
+            Dispatch::new()
+                .level(LevelFilter::Off)
+                .level_for("minimuxer", LevelFilter::Info)
+                .level_for("rusty_libimobiledevice", LevelFilter::Error)
+                .level_for("idevice", LevelFilter::Debug)
+                .chain(File::create(&log_path).unwrap()),
+        );
+
+        // apply logger
+        if logger.apply().is_ok() {
+            info!("Logger initialized!!");
         }
-    };
+    }
+}
 
-    listen(pairing_file);
-    start_beat();
+/// Starts the muxer and a heartbeat for every registered device
+fn launch() {
+    listen();
+    // Devices registered before starting are beaten too
+    for device in DEVICES.all() {
+        start_beat(device.udid);
+    }
 
     info!("minimuxer has started!");
     STARTED.store(true, Ordering::Relaxed);
-    Ok(())
+    connection::started();
+}
+
+/// Stops the muxer, heartbeats and auto mounter and forgets the registered devices, so `start`
+/// can be called again (with a different pairing file if needed). Heartbeat and mounter threads
+/// that are waiting on the device exit once it answers.
+pub fn stop() {
+    info!("Stopping minimuxer");
+    stop_listener();
+    stop_beats();
+    stop_auto_mounter();
+    stop_watching_pairing_files();
+    DEVICES.clear();
+    STARTED.store(false, Ordering::Relaxed);
+    connection::stopped();
+    info!("minimuxer has stopped");
 }
 
-/// Sets the current environment variable for libusbmuxd to localhost
+/// Points libusbmuxd at the muxer's listen address
 pub fn target_minimuxer_address() {
-    std::env::set_var("USBMUXD_SOCKET_ADDRESS", format!("127.0.0.1:{LISTEN_PORT}"));
+    let addr = current_config().listen_on();
+    std::env::set_var("USBMUXD_SOCKET_ADDRESS", addr.to_string());
 }
@@ -0,0 +1,142 @@
+// Jackson Coxson
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use log::{error, info, warn};
+
+/// Pairing records saved through `SavePairRecord`, keyed by their PairRecordID (the device UDID).
+/// Registered devices' pairing files aren't stored here.
+///
+/// Records are kept in memory. If a directory is given, records are also written there as
+/// `<PairRecordID>.plist` and loaded again on the next start.
+pub struct PairRecordStore {
+    records: Mutex<HashMap<String, Vec<u8>>>,
+    directory: Option<PathBuf>,
+}
+
+impl PairRecordStore {
+    pub fn new(directory: Option<PathBuf>) -> PairRecordStore {
+        let mut records = HashMap::new();
+
+        if let Some(directory) = &directory {
+            match std::fs::read_dir(directory) {
+                Ok(entries) => {
+                    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+                        if path.extension().and_then(|e| e.to_str()) != Some("plist") {
+                            continue;
+                        }
+                        let id = match path.file_stem().and_then(|s| s.to_str()) {
+                            Some(id) => id.to_string(),
+                            None => continue,
+                        };
+                        match std::fs::read(&path) {
+                            Ok(data) => {
+                                records.insert(id, data);
+                            }
+                            Err(e) => warn!("Failed to read pair record {path:?}: {e:?}"),
+                        }
+                    }
+                    info!("Loaded {} pair records from {directory:?}", records.len());
+                }
+                Err(e) => warn!("Failed to read pair record directory {directory:?}: {e:?}"),
+            }
+        }
+
+        PairRecordStore {
+            records: Mutex::new(records),
+            directory,
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<Vec<u8>> {
+        self.records.lock().unwrap().get(id).cloned()
+    }
+
+    /// Saves a record, persisting it if the store has a directory
+    pub fn save(&self, id: String, data: Vec<u8>) -> std::io::Result<()> {
+        // The ID ends up in a file name, so only allow what a UDID can contain
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid PairRecordID {id:?}"),
+            ));
+        }
+        if let Some(path) = self.path(&id) {
+            if let Some(directory) = &self.directory {
+                std::fs::create_dir_all(directory)?;
+            }
+            if let Err(e) = std::fs::write(&path, &data) {
+                error!("Failed to persist pair record to {path:?}: {e:?}");
+                return Err(e);
+            }
+        }
+        self.records.lock().unwrap().insert(id, data);
+        Ok(())
+    }
+
+    /// Deletes a record. Returns `false` if there was no record with that ID.
+    pub fn delete(&self, id: &str) -> std::io::Result<bool> {
+        if self.records.lock().unwrap().remove(id).is_none() {
+            return Ok(false);
+        }
+        if let Some(path) = self.path(id) {
+            match std::fs::remove_file(&path) {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    error!("Failed to remove persisted pair record {path:?}: {e:?}");
+                    return Err(e);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        self.directory
+            .as_ref()
+            .map(|d| d.join(format!("{id}.plist")))
+    }
+}
+
+static STORE: Mutex<Option<Arc<PairRecordStore>>> = Mutex::new(None);
+
+/// The store the muxer serves, kept across listener restarts. It's reloaded if the directory
+/// changed.
+pub fn shared_store(directory: Option<PathBuf>) -> Arc<PairRecordStore> {
+    let mut store = STORE.lock().unwrap();
+    match &*store {
+        Some(s) if s.directory == directory => s.clone(),
+        _ => {
+            let s = Arc::new(PairRecordStore::new(directory));
+            *store = Some(s.clone());
+            s
+        }
+    }
+}
+
+/// Drops the muxer's saved record for a device whose pairing file was just registered, so the
+/// registered one is what every client gets
+pub fn forget_saved_record(id: &str) {
+    let store = match STORE.lock().unwrap().clone() {
+        Some(s) => s,
+        None => return,
+    };
+    if let Ok(true) = store.delete(id) {
+        info!("Dropped the saved pair record for {id}, it's registered now");
+    }
+}
+
+/// The DeviceID advertised for a pair record. It's derived from the PairRecordID so a device keeps
+/// its ID across restarts, fits in the binary protocol's 32 bit field and is never 0.
+pub fn device_id(id: &str) -> u64 {
+    // 32 bit FNV-1a
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in id.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash.max(1) as u64
+}
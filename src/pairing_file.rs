@@ -0,0 +1,305 @@
+// Jackson Coxson
+
+use std::fmt::{self, Display};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use log::{error, info, warn};
+use plist::{Dictionary, Value};
+
+use crate::{heartbeat::start_beat, plist_to_bytes, registry::DeviceRegistry, Errors, Res};
+
+#[swift_bridge::bridge]
+mod ffi {
+    #[swift_bridge(already_declared, swift_name = "MinimuxerError")]
+    enum Errors {}
+
+    extern "Rust" {
+        fn validate_pairing_file(pairing_file: &[u8]) -> Result<String, Errors>;
+        fn convert_pairing_file(pairing_file: &[u8], binary: bool) -> Result<Vec<u8>, Errors>;
+    }
+}
+
+/// A pairing file, as made by jitterbugpair or saved by usbmuxd, with every field checked
+#[derive(Clone, Debug, PartialEq)]
+pub struct PairingFile {
+    pub udid: String,
+    pub host_id: String,
+    pub system_buid: String,
+    pub device_certificate: Vec<u8>,
+    pub host_certificate: Vec<u8>,
+    pub host_private_key: Vec<u8>,
+    pub root_certificate: Vec<u8>,
+    pub root_private_key: Vec<u8>,
+    /// Older pairing files don't have the MAC address
+    pub wifi_mac_address: Option<String>,
+    pub escrow_bag: Option<Vec<u8>>,
+    /// The dictionary the pairing file was parsed from, so fields we don't know about survive
+    /// conversion
+    raw: Dictionary,
+}
+
+/// Why a pairing file was rejected
+#[derive(Debug)]
+pub enum PairingFileError {
+    /// The bytes aren't a plist at all
+    Parse(plist::Error),
+    NotADictionary,
+    Missing(&'static str),
+    WrongType(&'static str),
+    Invalid(&'static str, &'static str),
+}
+
+impl Display for PairingFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PairingFileError::Parse(e) => write!(f, "pairing file isn't a valid plist: {e}"),
+            PairingFileError::NotADictionary => write!(f, "pairing file isn't a dictionary"),
+            PairingFileError::Missing(field) => write!(f, "pairing file is missing {field}"),
+            PairingFileError::WrongType(field) => {
+                write!(f, "{field} in the pairing file has the wrong type")
+            }
+            PairingFileError::Invalid(field, reason) => {
+                write!(f, "{field} in the pairing file is invalid: {reason}")
+            }
+        }
+    }
+}
+
+impl From<PairingFileError> for Errors {
+    fn from(e: PairingFileError) -> Self {
+        error!("Bad pairing file: {e}");
+        Errors::PairingFile(e.to_string())
+    }
+}
+
+impl PairingFile {
+    /// Parses a pairing file in either XML or binary plist format
+    pub fn from_bytes(bytes: &[u8]) -> Result<PairingFile, PairingFileError> {
+        match Value::from_reader(std::io::Cursor::new(bytes)) {
+            Ok(Value::Dictionary(d)) => PairingFile::from_dictionary(d),
+            Ok(_) => Err(PairingFileError::NotADictionary),
+            Err(e) => Err(PairingFileError::Parse(e)),
+        }
+    }
+
+    pub fn from_dictionary(raw: Dictionary) -> Result<PairingFile, PairingFileError> {
+        let wifi_mac_address = match raw.get("WiFiMACAddress") {
+            Some(m) => {
+                let mac = m
+                    .as_string()
+                    .ok_or(PairingFileError::WrongType("WiFiMACAddress"))?;
+                if !valid_mac_address(mac) {
+                    return Err(PairingFileError::Invalid(
+                        "WiFiMACAddress",
+                        "not a MAC address",
+                    ));
+                }
+                Some(mac.to_string())
+            }
+            None => None,
+        };
+        let escrow_bag = match raw.get("EscrowBag") {
+            Some(e) => Some(
+                e.as_data()
+                    .ok_or(PairingFileError::WrongType("EscrowBag"))?
+                    .to_vec(),
+            ),
+            None => None,
+        };
+
+        Ok(PairingFile {
+            udid: string(&raw, "UDID")?,
+            host_id: string(&raw, "HostID")?,
+            system_buid: string(&raw, "SystemBUID")?,
+            device_certificate: pem(&raw, "DeviceCertificate", "CERTIFICATE")?,
+            host_certificate: pem(&raw, "HostCertificate", "CERTIFICATE")?,
+            host_private_key: pem(&raw, "HostPrivateKey", "PRIVATE KEY")?,
+            root_certificate: pem(&raw, "RootCertificate", "CERTIFICATE")?,
+            root_private_key: pem(&raw, "RootPrivateKey", "PRIVATE KEY")?,
+            wifi_mac_address,
+            escrow_bag,
+            raw,
+        })
+    }
+
+    pub fn to_dictionary(&self) -> Dictionary {
+        let mut dict = self.raw.clone();
+        dict.insert("UDID".to_string(), self.udid.clone().into());
+        dict.insert("HostID".to_string(), self.host_id.clone().into());
+        dict.insert("SystemBUID".to_string(), self.system_buid.clone().into());
+        for (key, data) in [
+            ("DeviceCertificate", &self.device_certificate),
+            ("HostCertificate", &self.host_certificate),
+            ("HostPrivateKey", &self.host_private_key),
+            ("RootCertificate", &self.root_certificate),
+            ("RootPrivateKey", &self.root_private_key),
+        ] {
+            dict.insert(key.to_string(), Value::Data(data.clone()));
+        }
+        match &self.wifi_mac_address {
+            Some(m) => dict.insert("WiFiMACAddress".to_string(), m.clone().into()),
+            None => dict.remove("WiFiMACAddress"),
+        };
+        match &self.escrow_bag {
+            Some(e) => dict.insert("EscrowBag".to_string(), Value::Data(e.clone())),
+            None => dict.remove("EscrowBag"),
+        };
+        dict
+    }
+
+    pub fn to_xml(&self) -> Vec<u8> {
+        plist_to_bytes(&self.to_dictionary())
+    }
+
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        plist::to_writer_binary(&mut bytes, &self.to_dictionary()).unwrap();
+        bytes
+    }
+
+    /// Converts to idevice's pairing file for connecting to the device directly
+    pub fn to_idevice(&self) -> Res<idevice::pairing_file::PairingFile> {
+        match idevice::pairing_file::PairingFile::from_bytes(&self.to_xml()) {
+            Ok(p) => Ok(p),
+            Err(e) => {
+                error!("idevice rejected the pairing file for {}: {e:?}", self.udid);
+                Err(Errors::PairingFile(format!("{e:?}")))
+            }
+        }
+    }
+}
+
+fn string(dict: &Dictionary, field: &'static str) -> Result<String, PairingFileError> {
+    let value = dict
+        .get(field)
+        .ok_or(PairingFileError::Missing(field))?
+        .as_string()
+        .ok_or(PairingFileError::WrongType(field))?;
+    if value.is_empty() {
+        return Err(PairingFileError::Invalid(field, "empty"));
+    }
+    Ok(value.to_string())
+}
+
+/// Gets a PEM encoded certificate or key, checking that it holds the right kind of block
+fn pem(dict: &Dictionary, field: &'static str, kind: &str) -> Result<Vec<u8>, PairingFileError> {
+    let data = dict
+        .get(field)
+        .ok_or(PairingFileError::Missing(field))?
+        .as_data()
+        .ok_or(PairingFileError::WrongType(field))?;
+    let text = match std::str::from_utf8(data) {
+        Ok(t) => t.trim_start(),
+        Err(_) => return Err(PairingFileError::Invalid(field, "not PEM encoded")),
+    };
+    // keys can be "RSA PRIVATE KEY" or "PRIVATE KEY"
+    match text.lines().next() {
+        Some(l) if l.starts_with("-----BEGIN ") && l.ends_with(&format!("{kind}-----")) => {
+            Ok(data.to_vec())
+        }
+        _ => Err(PairingFileError::Invalid(field, "not PEM encoded")),
+    }
+}
+
+fn valid_mac_address(mac: &str) -> bool {
+    let bytes: Vec<&str> = mac.split(':').collect();
+    bytes.len() == 6
+        && bytes
+            .iter()
+            .all(|b| b.len() == 2 && u8::from_str_radix(b, 16).is_ok())
+}
+
+/// Checks a pairing file, returning its UDID or which field is bad
+pub fn validate_pairing_file(pairing_file: &[u8]) -> Res<String> {
+    Ok(PairingFile::from_bytes(pairing_file)?.udid)
+}
+
+/// Checks a pairing file and converts it to a binary or XML plist
+pub fn convert_pairing_file(pairing_file: &[u8], binary: bool) -> Res<Vec<u8>> {
+    let pairing_file = PairingFile::from_bytes(pairing_file)?;
+    Ok(match binary {
+        true => pairing_file.to_binary(),
+        false => pairing_file.to_xml(),
+    })
+}
+
+/// Reads and validates the pairing file at `path`
+pub fn read_pairing_file(path: &Path) -> Res<PairingFile> {
+    match std::fs::read(path) {
+        Ok(b) => Ok(PairingFile::from_bytes(&b)?),
+        Err(e) => {
+            error!("Failed to read pairing file at {}: {e:?}", path.display());
+            Err(Errors::PairingFile(format!(
+                "couldn't read {}: {e}",
+                path.display()
+            )))
+        }
+    }
+}
+
+/// Bumped by `stop_watching_pairing_files` to tell every watcher thread to exit
+static WATCH_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Watches the pairing file at `path` for the device `udid`, swapping it into the registry
+/// whenever it changes. Files that don't validate are logged and ignored, so the device keeps
+/// using the last good pairing file.
+pub fn watch_pairing_file(
+    devices: Arc<DeviceRegistry>,
+    path: PathBuf,
+    address: IpAddr,
+    mut udid: String,
+) {
+    const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+    let generation = WATCH_GENERATION.load(Ordering::Relaxed);
+    let modified =
+        |path: &Path| -> Option<SystemTime> { std::fs::metadata(path).ok()?.modified().ok() };
+    let mut last_modified = modified(&path);
+
+    std::thread::Builder::new()
+        .name("pairing-file-watcher".to_string())
+        .spawn(move || {
+            info!("Watching pairing file at {}", path.display());
+            loop {
+                std::thread::sleep(POLL_INTERVAL);
+                if WATCH_GENERATION.load(Ordering::Relaxed) != generation {
+                    info!("Stopped watching {}", path.display());
+                    return;
+                }
+
+                let current = modified(&path);
+                if current == last_modified {
+                    continue;
+                }
+                last_modified = current;
+
+                let pairing_file = match read_pairing_file(&path) {
+                    Ok(p) => p,
+                    Err(_) => {
+                        warn!("Keeping the previous pairing file for {udid}");
+                        continue;
+                    }
+                };
+                info!("Pairing file at {} changed, reloading", path.display());
+
+                // Re-pairing a different device replaces the old one
+                if pairing_file.udid != udid {
+                    devices.remove(&udid);
+                    udid = devices.add(pairing_file, address);
+                    start_beat(udid.clone());
+                } else {
+                    devices.add(pairing_file, address);
+                }
+            }
+        })
+        .unwrap();
+}
+
+/// Stops every pairing file watcher
+pub fn stop_watching_pairing_files() {
+    WATCH_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
@@ -5,7 +5,7 @@ use plist::Value;
 use plist_plus::Plist;
 
 use crate::{
-    device::{fetch_first_device, test_device_connection},
+    device::{fetch_device, test_device_connection},
     Errors, Res, RustyPlistConversion,
 };
 
@@ -15,24 +15,25 @@ mod ffi {
     enum Errors {}
 
     extern "Rust" {
-        fn install_provisioning_profile(profile: &[u8]) -> Result<(), Errors>;
-        fn remove_provisioning_profile(id: String) -> Result<(), Errors>;
-        fn dump_profiles(docs_path: String) -> Result<String, Errors>;
+        fn install_provisioning_profile(profile: &[u8], udid: Option<String>)
+            -> Result<(), Errors>;
+        fn remove_provisioning_profile(id: String, udid: Option<String>) -> Result<(), Errors>;
+        fn dump_profiles(docs_path: String, udid: Option<String>) -> Result<String, Errors>;
     }
 }
 
 // TODO: take a vec of provisioning profiles and remove old ones like AltServer
 /// Installs a provisioning profile on the device
 // pub fn install_provisioning_profile(profile: Vec<&[u8]>, bundle_ids: Vec<String>) -> Result<()> {
-pub fn install_provisioning_profile(profile: &[u8]) -> Res<()> {
+pub fn install_provisioning_profile(profile: &[u8], udid: Option<String>) -> Res<()> {
     info!("Installing provisioning profile");
 
-    if !test_device_connection() {
+    if !test_device_connection(udid.clone()) {
         error!("No device connection");
         return Err(Errors::NoConnection);
     }
 
-    let device = fetch_first_device()?;
+    let device = fetch_device(udid.as_deref())?;
 
     let mis_client = match device.new_misagent_client("minimuxer-install-prov") {
         Ok(m) => m,
@@ -59,15 +60,15 @@ pub fn install_provisioning_profile(profile: &[u8]) -> Res<()> {
 /// Removes a provisioning profile
 /// # Arguments
 /// - `id`: Profile UUID
-pub fn remove_provisioning_profile(id: String) -> Res<()> {
+pub fn remove_provisioning_profile(id: String, udid: Option<String>) -> Res<()> {
     info!("Removing profile with ID: {}", id);
 
-    if !test_device_connection() {
+    if !test_device_connection(udid.clone()) {
         error!("No device connection");
         return Err(Errors::NoConnection);
     }
 
-    let device = fetch_first_device()?;
+    let device = fetch_device(udid.as_deref())?;
 
     let mis_client = match device.new_misagent_client("minimuxer-install-prov") {
         Ok(m) => m,
@@ -89,15 +90,15 @@ pub fn remove_provisioning_profile(id: String) -> Res<()> {
     }
 }
 
-pub fn dump_profiles(docs_path: String) -> Res<String> {
+pub fn dump_profiles(docs_path: String, udid: Option<String>) -> Res<String> {
     info!("Dumping profiles");
 
-    if !test_device_connection() {
+    if !test_device_connection(udid.clone()) {
         error!("No device connection");
         return Err(Errors::NoConnection);
     }
 
-    let device = fetch_first_device()?;
+    let device = fetch_device(udid.as_deref())?;
 
     let mis_client = match device.new_misagent_client("minimuxer-install-prov") {
         Ok(m) => m,
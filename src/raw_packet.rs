@@ -1,118 +1,514 @@
 // jkcoxson
 
+//! usbmuxd frame encoding and decoding for the muxer
+
+use std::fmt::{self, Display};
+use std::io::{self, ErrorKind, Read, Write};
+
 use log::warn;
-use plist::Value;
+use plist::{Dictionary, Value};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::plist_to_bytes;
 
+/// The original usbmuxd protocol, with fixed-layout messages
+pub const BINARY_VERSION: u32 = 0;
+/// The usbmuxd protocol where every message is a plist
+pub const PLIST_VERSION: u32 = 1;
+
+/// How a plist message body is serialized
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlistFormat {
+    Xml,
+    Binary,
+}
+
+impl PlistFormat {
+    /// Works out the format of a serialized plist
+    pub fn detect(body: &[u8]) -> PlistFormat {
+        match body.starts_with(b"bplist00") {
+            true => PlistFormat::Binary,
+            false => PlistFormat::Xml,
+        }
+    }
+}
+
+/// The protocol version and plist format a client uses, so what we send it matches
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Encoding {
+    pub version: u32,
+    pub format: PlistFormat,
+}
+
+/// Binary protocol message types. Plist packets always use `MESSAGE_PLIST`.
+pub const MESSAGE_RESULT: u32 = 1;
+pub const MESSAGE_CONNECT: u32 = 2;
+pub const MESSAGE_LISTEN: u32 = 3;
+pub const MESSAGE_DEVICE_ADD: u32 = 4;
+pub const MESSAGE_DEVICE_REMOVE: u32 = 5;
+pub const MESSAGE_PLIST: u32 = 8;
+
+/// Length of the serial number in a binary `DeviceAdd` record, NUL terminator included
+const SERIAL_NUMBER_LENGTH: usize = 256;
+
+/// Frames bigger than this are rejected before their body is read. usbmuxd messages are a few
+/// kilobytes at most, so this only stops a bogus size from making us buffer forever.
+pub const MAX_FRAME_SIZE: u32 = 1024 * 1024;
+const HEADER_SIZE: usize = 16;
+
+/// Why a usbmuxd frame couldn't be read or decoded
 #[derive(Debug)]
-pub struct RawPacket {
+pub enum PacketError {
+    Io(io::Error),
+    /// The stream ended partway through a header, after this many bytes
+    ShortHeader(usize),
+    /// The stream ended before the frame did, the header's size can't even hold the header, or
+    /// there are bytes after the frame
+    SizeMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// The header's size is over the maximum frame size
+    Oversize(u32),
+    Plist(plist::Error),
+    /// A binary protocol message we don't know, or one that is too short
+    BadBinaryMessage(u32),
+}
+
+impl Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketError::Io(e) => write!(f, "io error: {e}"),
+            PacketError::ShortHeader(len) => write!(f, "stream ended after {len} header bytes"),
+            PacketError::SizeMismatch { expected, actual } => {
+                write!(f, "frame should be {expected} bytes but is {actual}")
+            }
+            PacketError::Oversize(size) => write!(f, "frame size {size} is over the maximum"),
+            PacketError::Plist(e) => write!(f, "couldn't decode plist: {e}"),
+            PacketError::BadBinaryMessage(m) => write!(f, "bad binary message {m}"),
+        }
+    }
+}
+
+impl From<io::Error> for PacketError {
+    fn from(e: io::Error) -> Self {
+        PacketError::Io(e)
+    }
+}
+
+/// The header in front of every usbmuxd frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketHeader {
+    /// Size of the whole frame, header included
     pub size: u32,
     pub version: u32,
     pub message: u32,
     pub tag: u32,
+}
+
+impl PacketHeader {
+    fn parse(bytes: &[u8]) -> PacketHeader {
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+        PacketHeader {
+            size: u32_at(0),
+            version: u32_at(4),
+            message: u32_at(8),
+            tag: u32_at(12),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&self.size.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.version.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.message.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.tag.to_le_bytes());
+        bytes
+    }
+}
+
+/// A frame that has been read but not decoded yet, so the header can be looked at first
+#[derive(Debug)]
+pub struct Frame {
+    pub header: PacketHeader,
+    pub body: Vec<u8>,
+}
+
+impl Frame {
+    pub fn decode(&self) -> Result<RawPacket, PacketError> {
+        let plist = match self.header.version {
+            BINARY_VERSION => decode_binary(self.header.message, &self.body)
+                .ok_or(PacketError::BadBinaryMessage(self.header.message))?,
+            _ => plist::from_bytes(&self.body).map_err(PacketError::Plist)?,
+        };
+        Ok(RawPacket {
+            version: self.header.version,
+            message: self.header.message,
+            tag: self.header.tag,
+            format: PlistFormat::detect(&self.body),
+            plist,
+        })
+    }
+}
+
+/// Incremental usbmuxd frame decoder: bytes go in as they arrive, whole frames come out
+pub struct PacketDecoder {
+    buffer: Vec<u8>,
+    max_frame_size: u32,
+}
+
+impl PacketDecoder {
+    pub fn new(max_frame_size: u32) -> PacketDecoder {
+        PacketDecoder {
+            buffer: vec![],
+            max_frame_size,
+        }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    fn header(&self) -> Option<PacketHeader> {
+        self.buffer.get(..HEADER_SIZE).map(PacketHeader::parse)
+    }
+
+    /// How many more bytes the current frame needs. Reading no more than this leaves whatever
+    /// follows the frame in the stream, which matters once a client's stream becomes a relay.
+    pub fn bytes_needed(&self) -> usize {
+        match self.header() {
+            Some(h) => (h.size as usize).saturating_sub(self.buffer.len()),
+            None => HEADER_SIZE - self.buffer.len(),
+        }
+    }
+
+    /// Takes the next whole frame out of the buffer, if it has arrived. The size is checked as
+    /// soon as the header is in, before any of the body is buffered.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, PacketError> {
+        let header = match self.header() {
+            Some(h) => h,
+            None => return Ok(None),
+        };
+        if (header.size as usize) < HEADER_SIZE {
+            return Err(PacketError::SizeMismatch {
+                expected: header.size,
+                actual: HEADER_SIZE as u32,
+            });
+        }
+        if header.size > self.max_frame_size {
+            return Err(PacketError::Oversize(header.size));
+        }
+        if self.buffer.len() < header.size as usize {
+            return Ok(None);
+        }
+
+        let rest = self.buffer.split_off(header.size as usize);
+        let frame = std::mem::replace(&mut self.buffer, rest);
+        Ok(Some(Frame {
+            header,
+            body: frame[HEADER_SIZE..].to_vec(),
+        }))
+    }
+
+    /// Checks that the stream didn't end partway through a frame
+    pub fn finish(&self) -> Result<(), PacketError> {
+        match self.header() {
+            _ if self.buffer.is_empty() => Ok(()),
+            None => Err(PacketError::ShortHeader(self.buffer.len())),
+            Some(h) => Err(PacketError::SizeMismatch {
+                expected: h.size,
+                actual: self.buffer.len() as u32,
+            }),
+        }
+    }
+}
+
+/// A usbmuxd packet. Binary packets are translated to and from the plist message they
+/// correspond to, so `plist` always holds the message regardless of the protocol version.
+#[derive(Debug)]
+pub struct RawPacket {
+    pub version: u32,
+    pub message: u32,
+    pub tag: u32,
+    /// The format the plist was received in or will be sent in. Unused by the binary protocol.
+    pub format: PlistFormat,
     pub plist: Value,
 }
 
 impl RawPacket {
+    /// Builds a packet that will be sent as an XML plist
     pub fn new(plist: Value, version: u32, message: u32, tag: u32) -> RawPacket {
-        let size = plist_to_bytes(&plist).len() as u32 + 16;
         RawPacket {
-            size,
             version,
             message,
             tag,
+            format: PlistFormat::Xml,
             plist,
         }
     }
+
+    /// Builds a packet encoded the way the client expects, taking the binary message type from
+    /// the plist's `MessageType`
+    pub fn with_encoding(plist: Value, encoding: Encoding, tag: u32) -> RawPacket {
+        let message = match encoding.version {
+            BINARY_VERSION => binary_message_type(&plist),
+            _ => MESSAGE_PLIST,
+        };
+        RawPacket {
+            format: encoding.format,
+            ..RawPacket::new(plist, encoding.version, message, tag)
+        }
+    }
+
+    /// Serializes the packet into a frame, header included
+    pub fn encode(&self) -> Vec<u8> {
+        let body = encode_body(&self.plist, self.version, self.format);
+        let header = PacketHeader {
+            size: (HEADER_SIZE + body.len()) as u32,
+            version: self.version,
+            message: self.message,
+            tag: self.tag,
+        };
+        let mut frame = header.to_bytes().to_vec();
+        frame.extend_from_slice(&body);
+        frame
+    }
 }
 
-impl From<RawPacket> for Vec<u8> {
-    fn from(raw_packet: RawPacket) -> Vec<u8> {
-        let mut packet = vec![];
-        packet.extend_from_slice(&raw_packet.size.to_le_bytes());
-        packet.extend_from_slice(&raw_packet.version.to_le_bytes());
-        packet.extend_from_slice(&raw_packet.message.to_le_bytes());
-        packet.extend_from_slice(&raw_packet.tag.to_le_bytes());
-        packet.extend_from_slice(plist_to_bytes(&raw_packet.plist).as_slice());
-        packet
+fn binary_message_type(plist: &Value) -> u32 {
+    let message_type = plist
+        .as_dictionary()
+        .and_then(|d| d.get("MessageType"))
+        .and_then(|m| m.as_string());
+    match message_type {
+        Some("Result") => MESSAGE_RESULT,
+        Some("Connect") => MESSAGE_CONNECT,
+        Some("Listen") => MESSAGE_LISTEN,
+        Some("Attached") => MESSAGE_DEVICE_ADD,
+        Some("Detached") => MESSAGE_DEVICE_REMOVE,
+        _ => MESSAGE_PLIST,
     }
 }
 
-impl TryFrom<&mut Vec<u8>> for RawPacket {
-    type Error = ();
-    fn try_from(packet: &mut Vec<u8>) -> Result<Self, Self::Error> {
-        let packet: &[u8] = packet;
-        packet.try_into()
+fn encode_body(plist: &Value, version: u32, format: PlistFormat) -> Vec<u8> {
+    if version != BINARY_VERSION {
+        return match format {
+            PlistFormat::Xml => plist_to_bytes(plist),
+            PlistFormat::Binary => {
+                let mut bytes = vec![];
+                plist.to_writer_binary(&mut bytes).unwrap();
+                bytes
+            }
+        };
+    }
+    match encode_binary(plist) {
+        Some(b) => b,
+        None => {
+            warn!("Message has no binary protocol equivalent: {plist:?}");
+            vec![]
+        }
     }
 }
 
-impl TryFrom<&[u8]> for RawPacket {
-    type Error = ();
-    fn try_from(packet: &[u8]) -> Result<Self, Self::Error> {
-        // Determine if we have enough data to parse
-        if packet.len() < 16 {
-            warn!("Not enough data to parse a raw packet header");
-            return Err(());
-        }
-
-        let packet_size = &packet[0..4];
-        let packet_size = u32::from_le_bytes(match packet_size.try_into() {
-            Ok(packet_size) => packet_size,
-            Err(e) => {
-                warn!("Failed to parse packet size: {e:?}");
-                return Err(());
+/// Encodes a message in the binary protocol's fixed layout
+fn encode_binary(plist: &Value) -> Option<Vec<u8>> {
+    let message = plist.as_dictionary()?;
+    let number = |dict: &Dictionary, key: &str| {
+        dict.get(key)
+            .and_then(|v| v.as_unsigned_integer())
+            .unwrap_or(0)
+    };
+
+    let mut body = vec![];
+    match binary_message_type(plist) {
+        MESSAGE_RESULT => body.extend_from_slice(&(number(message, "Number") as u32).to_le_bytes()),
+        MESSAGE_CONNECT => {
+            body.extend_from_slice(&(number(message, "DeviceID") as u32).to_le_bytes());
+            // Already in network byte order, just like in the plist protocol
+            body.extend_from_slice(&(number(message, "PortNumber") as u16).to_le_bytes());
+            body.extend_from_slice(&[0, 0]);
+        }
+        MESSAGE_LISTEN => {}
+        MESSAGE_DEVICE_ADD => {
+            let properties = message.get("Properties").and_then(|p| p.as_dictionary())?;
+            body.extend_from_slice(&(number(message, "DeviceID") as u32).to_le_bytes());
+            body.extend_from_slice(&(number(properties, "ProductID") as u16).to_le_bytes());
+            let mut serial = [0u8; SERIAL_NUMBER_LENGTH];
+            if let Some(s) = properties.get("SerialNumber").and_then(|s| s.as_string()) {
+                let len = s.len().min(SERIAL_NUMBER_LENGTH - 1);
+                serial[..len].copy_from_slice(&s.as_bytes()[..len]);
             }
-        });
+            body.extend_from_slice(&serial);
+            body.extend_from_slice(&[0, 0]);
+            body.extend_from_slice(&(number(properties, "LocationID") as u32).to_le_bytes());
+        }
+        MESSAGE_DEVICE_REMOVE => {
+            body.extend_from_slice(&(number(message, "DeviceID") as u32).to_le_bytes())
+        }
+        _ => return None,
+    }
+    Some(body)
+}
 
-        // Determine if we have enough data to parse
-        if packet.len() < packet_size as usize {
-            warn!("Not enough data to parse a raw packet body");
-            return Err(());
+/// Decodes a binary protocol message into the plist message it corresponds to
+fn decode_binary(message: u32, body: &[u8]) -> Option<Value> {
+    let u32_at = |offset: usize| -> Option<u64> {
+        let bytes = body.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().ok()?) as u64)
+    };
+    let u16_at = |offset: usize| -> Option<u64> {
+        let bytes = body.get(offset..offset + 2)?;
+        Some(u16::from_le_bytes(bytes.try_into().ok()?) as u64)
+    };
+
+    let mut output = Dictionary::new();
+    match message {
+        MESSAGE_RESULT => {
+            output.insert("MessageType".to_string(), "Result".into());
+            output.insert("Number".to_string(), u32_at(0)?.into());
+        }
+        MESSAGE_CONNECT => {
+            output.insert("MessageType".to_string(), "Connect".into());
+            output.insert("DeviceID".to_string(), u32_at(0)?.into());
+            output.insert("PortNumber".to_string(), u16_at(4)?.into());
         }
+        MESSAGE_LISTEN => {
+            output.insert("MessageType".to_string(), "Listen".into());
+        }
+        MESSAGE_DEVICE_ADD => {
+            let serial = body.get(6..6 + SERIAL_NUMBER_LENGTH)?;
+            let serial_len = serial.iter().position(|b| *b == 0).unwrap_or(serial.len());
+            let serial = String::from_utf8_lossy(&serial[..serial_len]).to_string();
 
-        let packet_version = &packet[4..8];
-        let packet_version = u32::from_le_bytes(match packet_version.try_into() {
-            Ok(packet_version) => packet_version,
-            Err(e) => {
-                warn!("Failed to parse packet version: {e:?}");
-                return Err(());
-            }
-        });
-
-        let message = &packet[8..12];
-        let message = u32::from_le_bytes(match message.try_into() {
-            Ok(message) => message,
-            Err(e) => {
-                warn!("Failed to parse packet message: {e:?}");
-                return Err(());
-            }
-        });
-
-        let packet_tag = &packet[12..16];
-        let packet_tag = u32::from_le_bytes(match packet_tag.try_into() {
-            Ok(packet_tag) => packet_tag,
-            Err(e) => {
-                warn!("Failed to parse packet tag: {e:?}");
-                return Err(());
-            }
-        });
-
-        let plist = &packet[16..packet_size as usize];
-        let plist: Value = match plist::from_bytes(plist) {
-            Ok(p) => p,
-            Err(e) => {
-                warn!("Failed to parse packet plist: {e:?}");
-                return Err(());
-            }
-        };
+            let mut properties = Dictionary::new();
+            properties.insert("DeviceID".to_string(), u32_at(0)?.into());
+            properties.insert("ProductID".to_string(), u16_at(4)?.into());
+            properties.insert("SerialNumber".to_string(), serial.into());
+            properties.insert(
+                "LocationID".to_string(),
+                u32_at(8 + SERIAL_NUMBER_LENGTH)?.into(),
+            );
+            output.insert("MessageType".to_string(), "Attached".into());
+            output.insert("DeviceID".to_string(), u32_at(0)?.into());
+            output.insert("Properties".to_string(), properties.into());
+        }
+        MESSAGE_DEVICE_REMOVE => {
+            output.insert("MessageType".to_string(), "Detached".into());
+            output.insert("DeviceID".to_string(), u32_at(0)?.into());
+        }
+        _ => return None,
+    }
+    Some(output.into())
+}
 
-        Ok(RawPacket {
-            size: packet_size,
-            version: packet_version,
-            message,
-            tag: packet_tag,
-            plist,
-        })
+/// Reads one frame, without reading past its end.
+///
+/// Returns `Ok(None)` if the reader was closed before a new frame started.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_frame_size: u32,
+) -> Result<Option<Frame>, PacketError> {
+    let mut decoder = PacketDecoder::new(max_frame_size);
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(frame) = decoder.next_frame()? {
+            return Ok(Some(frame));
+        }
+        let wanted = decoder.bytes_needed().min(chunk.len());
+        match reader.read(&mut chunk[..wanted]).await? {
+            0 if decoder.buffer.is_empty() => return Ok(None),
+            0 => return decoder.finish().map(|_| None),
+            read => decoder.feed(&chunk[..read]),
+        }
+    }
+}
+
+/// Blocking version of `read_frame`, for code that isn't running on the runtime
+#[allow(dead_code)]
+pub fn read_frame_blocking<R: Read>(
+    reader: &mut R,
+    max_frame_size: u32,
+) -> Result<Option<Frame>, PacketError> {
+    let mut decoder = PacketDecoder::new(max_frame_size);
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(frame) = decoder.next_frame()? {
+            return Ok(Some(frame));
+        }
+        let wanted = decoder.bytes_needed().min(chunk.len());
+        match reader.read(&mut chunk[..wanted]) {
+            Ok(0) if decoder.buffer.is_empty() => return Ok(None),
+            Ok(0) => return decoder.finish().map(|_| None),
+            Ok(read) => decoder.feed(&chunk[..read]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Reads and decodes one packet. Returns `Ok(None)` if the reader was closed first.
+#[allow(dead_code)]
+pub async fn read_packet<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<RawPacket>, PacketError> {
+    match read_frame(reader, MAX_FRAME_SIZE).await? {
+        Some(frame) => frame.decode().map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Blocking version of `read_packet`
+#[allow(dead_code)]
+pub fn read_packet_blocking<R: Read>(reader: &mut R) -> Result<Option<RawPacket>, PacketError> {
+    match read_frame_blocking(reader, MAX_FRAME_SIZE)? {
+        Some(frame) => frame.decode().map(Some),
+        None => Ok(None),
+    }
+}
+
+pub async fn write_packet<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    packet: &RawPacket,
+) -> io::Result<()> {
+    writer.write_all(&packet.encode()).await
+}
+
+/// Blocking version of `write_packet`
+#[allow(dead_code)]
+pub fn write_packet_blocking<W: Write>(writer: &mut W, packet: &RawPacket) -> io::Result<()> {
+    writer.write_all(&packet.encode())
+}
+
+impl From<RawPacket> for Vec<u8> {
+    fn from(raw_packet: RawPacket) -> Vec<u8> {
+        raw_packet.encode()
+    }
+}
+
+/// Decodes exactly one frame. Fails if the slice holds less or more than that.
+impl TryFrom<&[u8]> for RawPacket {
+    type Error = PacketError;
+    fn try_from(packet: &[u8]) -> Result<Self, Self::Error> {
+        let mut decoder = PacketDecoder::new(u32::MAX);
+        decoder.feed(packet);
+        match decoder.next_frame()? {
+            Some(frame) if frame.header.size as usize != packet.len() => {
+                Err(PacketError::SizeMismatch {
+                    expected: frame.header.size,
+                    actual: packet.len() as u32,
+                })
+            }
+            Some(frame) => frame.decode(),
+            None => decoder
+                .finish()
+                .and(Err(PacketError::ShortHeader(packet.len()))),
+        }
     }
 }
@@ -0,0 +1,258 @@
+// Jackson Coxson
+
+use std::net::IpAddr;
+use std::sync::{atomic::Ordering, Arc, RwLock};
+use std::time::Duration;
+
+use log::info;
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+
+use crate::{
+    config::parse_address,
+    device_info::DeviceInfo,
+    heartbeat::{start_beat, HeartbeatMetrics},
+    muxer::STARTED,
+    pair_records::{device_id, forget_saved_record},
+    pairing_file::PairingFile,
+    Res,
+};
+
+#[swift_bridge::bridge]
+mod ffi {
+    #[swift_bridge(already_declared, swift_name = "MinimuxerError")]
+    enum Errors {}
+
+    extern "Rust" {
+        fn add_device(pairing_file: String, address: String) -> Result<String, Errors>;
+        fn remove_device(udid: String) -> bool;
+        fn registered_devices() -> Vec<String>;
+    }
+}
+
+/// Every device minimuxer knows about, served by the muxer and targeted by the other modules
+pub static DEVICES: Lazy<Arc<DeviceRegistry>> = Lazy::new(|| Arc::new(DeviceRegistry::new()));
+
+#[derive(Clone, Debug)]
+pub struct RegisteredDevice {
+    pub udid: String,
+    pub device_id: u64,
+    pub pairing_file: PairingFile,
+    /// The address the device is reachable at
+    pub address: IpAddr,
+    /// Whether the last heartbeat to the device succeeded
+    pub attached: bool,
+    pub heartbeat: HeartbeatMetrics,
+    /// Whether the auto mounter found or mounted the developer disk image on the device
+    pub dmg_mounted: bool,
+    /// What lockdown said about the device, cached until it disconnects
+    pub device_info: Option<DeviceInfo>,
+    /// Bumped whenever the pairing file is replaced, so open sessions know to reconnect
+    pub revision: u64,
+}
+
+/// A registered device was attached or detached
+#[derive(Clone, Debug)]
+pub struct DeviceEvent {
+    pub udid: String,
+    pub device_id: u64,
+    pub attached: bool,
+}
+
+/// Pairing files mapped to the addresses of their devices, in the order they were added
+pub struct DeviceRegistry {
+    devices: RwLock<Vec<RegisteredDevice>>,
+    events: broadcast::Sender<DeviceEvent>,
+}
+
+impl Default for DeviceRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceRegistry {
+    pub fn new() -> DeviceRegistry {
+        DeviceRegistry {
+            devices: RwLock::new(vec![]),
+            events: broadcast::channel(16).0,
+        }
+    }
+
+    /// Adds a device, replacing the one with the same UDID if there is one. Any pair record saved
+    /// through the muxer for it is dropped. Returns the UDID.
+    pub fn add(&self, pairing_file: PairingFile, address: IpAddr) -> String {
+        let udid = pairing_file.udid.clone();
+        let mut devices = self.devices.write().unwrap();
+        match devices.iter_mut().find(|d| d.udid == udid) {
+            Some(device) => {
+                info!("Updating device {udid} at {address}");
+                if device.pairing_file != pairing_file {
+                    device.revision += 1;
+                    device.device_info = None;
+                }
+                device.pairing_file = pairing_file;
+                device.address = address;
+            }
+            None => {
+                info!("Adding device {udid} at {address}");
+                devices.push(RegisteredDevice {
+                    device_id: device_id(&udid),
+                    udid: udid.clone(),
+                    pairing_file,
+                    address,
+                    attached: false,
+                    heartbeat: HeartbeatMetrics::default(),
+                    dmg_mounted: false,
+                    device_info: None,
+                    revision: 0,
+                });
+            }
+        }
+        forget_saved_record(&udid);
+        udid
+    }
+
+    /// Removes a device. Returns `false` if it wasn't registered.
+    pub fn remove(&self, udid: &str) -> bool {
+        let mut devices = self.devices.write().unwrap();
+        let index = match devices.iter().position(|d| d.udid == udid) {
+            Some(i) => i,
+            None => return false,
+        };
+        let device = devices.remove(index);
+        info!("Removed device {udid}");
+
+        if device.attached {
+            let _ = self.events.send(DeviceEvent {
+                udid: device.udid,
+                device_id: device.device_id,
+                attached: false,
+            });
+        }
+        true
+    }
+
+    /// Removes every device
+    pub fn clear(&self) {
+        for device in self.all() {
+            self.remove(&device.udid);
+        }
+    }
+
+    /// Gets a device by UDID, or the first device added if no UDID is given
+    pub fn get(&self, udid: Option<&str>) -> Option<RegisteredDevice> {
+        let devices = self.devices.read().unwrap();
+        match udid {
+            Some(udid) => devices.iter().find(|d| d.udid == udid).cloned(),
+            None => devices.first().cloned(),
+        }
+    }
+
+    pub fn get_by_device_id(&self, device_id: u64) -> Option<RegisteredDevice> {
+        let devices = self.devices.read().unwrap();
+        devices.iter().find(|d| d.device_id == device_id).cloned()
+    }
+
+    pub fn all(&self) -> Vec<RegisteredDevice> {
+        self.devices.read().unwrap().clone()
+    }
+
+    /// Records whether a device is reachable. Subscribers only hear about changes, so this can be
+    /// called on every heartbeat.
+    pub fn set_attached(&self, udid: &str, attached: bool) {
+        let mut devices = self.devices.write().unwrap();
+        let device = match devices.iter_mut().find(|d| d.udid == udid) {
+            Some(d) => d,
+            None => return,
+        };
+        if device.attached == attached {
+            return;
+        }
+        device.attached = attached;
+        // the device may have been restored or updated while it was away
+        device.device_info = None;
+
+        info!(
+            "Device {udid} {}",
+            if attached { "attached" } else { "detached" }
+        );
+        // there may not be any subscribers, which isn't a problem
+        let _ = self.events.send(DeviceEvent {
+            udid: udid.to_string(),
+            device_id: device.device_id,
+            attached,
+        });
+    }
+
+    /// Records the result of a heartbeat (how late it was, or why it failed), then whether the
+    /// device is attached
+    pub fn record_beat(&self, udid: &str, result: Result<Option<Duration>, String>) {
+        let attached = result.is_ok();
+        {
+            let mut devices = self.devices.write().unwrap();
+            let device = match devices.iter_mut().find(|d| d.udid == udid) {
+                Some(d) => d,
+                None => return,
+            };
+            match result {
+                Ok(delay) => device.heartbeat.record_success(delay),
+                Err(e) => device.heartbeat.record_failure(e),
+            }
+        }
+        self.set_attached(udid, attached);
+    }
+
+    pub fn cache_device_info(&self, udid: &str, info: DeviceInfo) {
+        let mut devices = self.devices.write().unwrap();
+        if let Some(device) = devices.iter_mut().find(|d| d.udid == udid) {
+            device.device_info = Some(info);
+        }
+    }
+
+    /// Records whether the developer disk image is mounted on the device, or on every device if
+    /// no UDID is given
+    pub fn set_mounted(&self, udid: Option<&str>, mounted: bool) {
+        let mut devices = self.devices.write().unwrap();
+        for device in devices.iter_mut() {
+            if udid.is_none() || udid == Some(device.udid.as_str()) {
+                device.dmg_mounted = mounted;
+            }
+        }
+    }
+
+    /// Records that the heartbeat had to start a new session with the device
+    pub fn record_reconnect(&self, udid: &str) {
+        let mut devices = self.devices.write().unwrap();
+        if let Some(device) = devices.iter_mut().find(|d| d.udid == udid) {
+            device.heartbeat.reconnects += 1;
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<DeviceEvent> {
+        self.events.subscribe()
+    }
+}
+
+/// Parses a pairing file and adds it to the registry as the device at `address`. Returns the
+/// device's UDID.
+pub fn add_device(pairing_file: String, address: String) -> Res<String> {
+    let pairing_file = PairingFile::from_bytes(pairing_file.as_bytes())?;
+    let address = parse_address(&address)?;
+
+    let udid = DEVICES.add(pairing_file, address);
+    // Devices added before starting get their heartbeat when minimuxer starts
+    if STARTED.load(Ordering::Relaxed) {
+        start_beat(udid.clone());
+    }
+    Ok(udid)
+}
+
+pub fn remove_device(udid: String) -> bool {
+    DEVICES.remove(&udid)
+}
+
+/// The UDIDs of every registered device
+pub fn registered_devices() -> Vec<String> {
+    DEVICES.all().into_iter().map(|d| d.udid).collect()
+}
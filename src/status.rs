@@ -0,0 +1,117 @@
+// Jackson Coxson
+
+use std::sync::atomic::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::info;
+
+use crate::{
+    device::{fetch_device, test_device_connection},
+    heartbeat::last_beat_successful,
+    muxer::STARTED,
+    registry::DEVICES,
+};
+
+#[swift_bridge::bridge]
+mod ffi {
+    extern "Rust" {
+        type MinimuxerStatus;
+
+        fn status(udid: Option<String>) -> MinimuxerStatus;
+
+        fn ready(self: &MinimuxerStatus) -> bool;
+        fn started(self: &MinimuxerStatus) -> bool;
+        fn device_registered(self: &MinimuxerStatus) -> bool;
+        fn device_connection(self: &MinimuxerStatus) -> bool;
+        fn device_exists(self: &MinimuxerStatus) -> bool;
+        fn heartbeat(self: &MinimuxerStatus) -> bool;
+        fn dmg_mounted(self: &MinimuxerStatus) -> bool;
+        fn last_success_timestamp(self: &MinimuxerStatus) -> Option<u64>;
+        fn last_error(self: &MinimuxerStatus) -> Option<String>;
+    }
+}
+
+/// The result of each check `ready` makes, so the UI can say which one failed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MinimuxerStatus {
+    /// `start` has been called and it was successful
+    pub started: bool,
+    /// A pairing file was added for the device
+    pub device_registered: bool,
+    /// lockdownd answered at the device's address (the VPN is on)
+    pub device_connection: bool,
+    /// The muxer returned the device
+    pub device_exists: bool,
+    /// The last heartbeat was a success
+    pub heartbeat: bool,
+    /// Not counted towards `ready`
+    pub dmg_mounted: bool,
+    /// When the last successful heartbeat happened
+    pub last_success: Option<SystemTime>,
+    /// Why the last failed heartbeat failed
+    pub last_error: Option<String>,
+}
+
+impl MinimuxerStatus {
+    /// Whether minimuxer is ready for the device. The developer disk image doesn't count.
+    pub fn ready(&self) -> bool {
+        self.started && self.device_connection && self.device_exists && self.heartbeat
+    }
+
+    pub fn started(&self) -> bool {
+        self.started
+    }
+
+    pub fn device_registered(&self) -> bool {
+        self.device_registered
+    }
+
+    pub fn device_connection(&self) -> bool {
+        self.device_connection
+    }
+
+    pub fn device_exists(&self) -> bool {
+        self.device_exists
+    }
+
+    pub fn heartbeat(&self) -> bool {
+        self.heartbeat
+    }
+
+    pub fn dmg_mounted(&self) -> bool {
+        self.dmg_mounted
+    }
+
+    /// Milliseconds since the Unix epoch
+    pub fn last_success_timestamp(&self) -> Option<u64> {
+        self.last_success
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as u64)
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.clone()
+    }
+}
+
+/// Checks whether minimuxer is ready for the device (the first registered device if no UDID is
+/// given)
+pub fn status(udid: Option<String>) -> MinimuxerStatus {
+    let device = DEVICES.get(udid.as_deref());
+    let status = MinimuxerStatus {
+        started: STARTED.load(Ordering::Relaxed),
+        device_registered: device.is_some(),
+        device_connection: test_device_connection(udid.clone()),
+        device_exists: fetch_device(udid.as_deref()).is_ok(),
+        heartbeat: last_beat_successful(udid.as_deref()),
+        dmg_mounted: device.as_ref().is_some_and(|d| d.dmg_mounted),
+        last_success: device.as_ref().and_then(|d| d.heartbeat.last_success),
+        last_error: device.and_then(|d| d.heartbeat.last_error),
+    };
+
+    match status.ready() {
+        true => info!("minimuxer is ready!"),
+        false => info!("minimuxer is not ready: {status:?}"),
+    }
+    status
+}